@@ -0,0 +1,216 @@
+//! Decoding and encoding over tokio's `AsyncRead` and `AsyncWrite`, enabled with the
+//! `async` feature
+
+use std::io::{self, BufWriter};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    decoder::{check_header, DecoderOptions, DecoderState, PngDecoder},
+    errors::{PngDecodingError, PngEncodingError},
+    Png,
+};
+
+async fn read_header<T: AsyncRead + Unpin>(f: &mut T) -> Result<(), PngDecodingError> {
+    let mut header = [0u8; 8];
+    f.read_exact(&mut header).await?;
+    check_header(header)
+}
+
+async fn read_chunk_header<T: AsyncRead + Unpin>(
+    f: &mut T,
+) -> Result<(u32, [u8; 4]), PngDecodingError> {
+    let length = f.read_u32().await?;
+
+    let mut chunk_type = [0u8; 4];
+    f.read_exact(&mut chunk_type).await?;
+
+    Ok((length, chunk_type))
+}
+
+async fn read_chunk_data<T: AsyncRead + Unpin>(
+    f: &mut T,
+    state: &mut DecoderState,
+    length: u32,
+    chunk_type: [u8; 4],
+) -> Result<(Vec<u8>, u32), PngDecodingError> {
+    // only allocate as much as is actually present, rather than trusting `length`
+    let mut data: Vec<u8> = Vec::new();
+    (&mut *f)
+        .take(u64::from(length))
+        .read_to_end(&mut data)
+        .await?;
+    if data.len() != length as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    let crc = f.read_u32().await?;
+    state.check_chunk_crc(chunk_type, &data, crc)?;
+
+    Ok((data, crc))
+}
+
+async fn read_chunks<T: AsyncRead + Unpin>(
+    f: &mut T,
+    state: &mut DecoderState,
+    idat: &mut Vec<u8>,
+) -> Result<(), PngDecodingError> {
+    read_header(f).await?;
+
+    loop {
+        state.start_chunk();
+        let (length, chunk_type) = read_chunk_header(f).await?;
+        state.check_length(length, chunk_type)?;
+        let accepted = state.check_order(chunk_type)?;
+        if !accepted {
+            skip_chunk_data(f, state, length, chunk_type).await?;
+            continue;
+        }
+
+        let (data, crc) = read_chunk_data(f, state, length, chunk_type).await?;
+        if state.read_chunk(chunk_type, &data, crc, idat)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Read past the data and CRC of a chunk, checking the CRC without storing the data
+async fn skip_chunk_data<T: AsyncRead + Unpin>(
+    f: &mut T,
+    state: &mut DecoderState,
+    length: u32,
+    chunk_type: [u8; 4],
+) -> Result<(), PngDecodingError> {
+    let mut hasher = state.chunk_hasher(chunk_type);
+
+    let mut buffer = [0u8; 4096];
+    let mut remaining = length as usize;
+    while remaining > 0 {
+        let len = std::cmp::min(remaining, buffer.len());
+        let read = f.read(&mut buffer[..len]).await?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buffer[..read]);
+        }
+        remaining -= read;
+    }
+
+    let crc = f.read_u32().await?;
+    state.check_skipped_crc(chunk_type, hasher, crc)
+}
+
+impl PngDecoder {
+    pub async fn read_async<T: AsyncRead + Unpin>(f: T) -> Result<Png, PngDecodingError> {
+        PngDecoder::read_async_with_options(f, &DecoderOptions::default()).await
+    }
+
+    /// Read a file without blocking the executor while waiting for data.
+    ///
+    /// Chunks are parsed exactly as they are by `read_with_options`
+    pub async fn read_async_with_options<T: AsyncRead + Unpin>(
+        mut f: T,
+        options: &DecoderOptions,
+    ) -> Result<Png, PngDecodingError> {
+        let mut state = DecoderState::new(options);
+        let mut idat: Vec<u8> = Vec::new();
+
+        match read_chunks(&mut f, &mut state, &mut idat).await {
+            Ok(()) => Ok(state.into_png(idat)),
+            Err(err) => Err(state.locate(err)),
+        }
+    }
+}
+
+impl Png {
+    /// Encode the image, then write it without blocking the executor.
+    ///
+    /// Compression happens up front on the calling task, so only the writing
+    /// itself is asynchronous
+    pub async fn write_async<T: AsyncWrite + Unpin>(
+        &self,
+        buffer: &mut T,
+    ) -> Result<(), PngEncodingError> {
+        let mut encoded = Vec::new();
+        self.write(&mut BufWriter::new(&mut encoded))?;
+
+        buffer.write_all(&encoded).await?;
+        buffer.flush().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::ColorType,
+        decoder::{ChunkPolicy, CrcCheck},
+        errors::ChunkError,
+        test_utils, PngBuilder,
+    };
+
+    #[tokio::test]
+    async fn read_from_duplex_stream() {
+        let file = test_utils::png(
+            2,
+            2,
+            8,
+            ColorType::Grayscale,
+            &[test_utils::chunk(b"tEXt", b"Title\0async")],
+            &[1, 2, 3, 4],
+        );
+
+        // a small buffer means the writer has to wait for the reader to catch up
+        let (mut writer, reader) = tokio::io::duplex(5);
+        let write = async move {
+            writer.write_all(&file).await.unwrap();
+        };
+
+        let (png, ()) = tokio::join!(PngDecoder::read_async(reader), write);
+        let png = png.unwrap();
+
+        assert_eq!(png.ancillary_chunks.tEXt[0].text, "async");
+        assert_eq!(png.decode().unwrap().buffer, vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn skipped_chunks_follow_crc_check() {
+        let mut text = test_utils::chunk(b"tEXt", b"Title\0async");
+        let last = text.len() - 1;
+        text[last] ^= 1;
+        let file = test_utils::png(1, 1, 8, ColorType::Grayscale, &[text], &[9]);
+
+        let options = DecoderOptions::new().chunk_policy(*b"tEXt", ChunkPolicy::Ignore);
+        let png = PngDecoder::read_async_with_options(file.as_slice(), &options).await;
+        assert!(matches!(
+            png.map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::ChunkError(ChunkError::CrcMismatch { .. }))
+        ));
+
+        let options = options.crc_check(CrcCheck::Skip);
+        let png = PngDecoder::read_async_with_options(file.as_slice(), &options).await;
+        assert!(png.unwrap().ancillary_chunks.tEXt.is_empty());
+    }
+
+    #[tokio::test]
+    async fn write_and_read_back() {
+        let png = PngBuilder::new(3, 1)
+            .color_type(ColorType::RGB)
+            .buffer(vec![10, 20, 30, 40, 50, 60, 70, 80, 90])
+            .finish();
+
+        let (mut writer, reader) = tokio::io::duplex(16);
+        let write = async move { png.write_async(&mut writer).await };
+
+        let (decoded, written) = tokio::join!(PngDecoder::read_async(reader), write);
+        written.unwrap();
+
+        assert_eq!(
+            decoded.unwrap().decode().unwrap().buffer,
+            vec![10, 20, 30, 40, 50, 60, 70, 80, 90]
+        );
+    }
+}
@@ -6,7 +6,8 @@ use std::{
 
 use crate::{
     common::ColorType,
-    errors::{ChunkError, MetadataError, PngDecodingError},
+    errors::{ChunkError, MetadataError, PngDecodingError, PngEncodingError},
+    interlacing,
 };
 
 /// The IHDR chunk contains important metadata for reading the image
@@ -77,6 +78,25 @@ impl IHDR {
             interlace_method,
         })
     }
+
+    /// The number of bits per complete pixel
+    pub fn bits_per_pixel(&self) -> usize {
+        usize::from(self.bit_depth) * usize::from(self.color_type.channels())
+    }
+
+    /// The number of bytes of decompressed image data, including filter type bytes.
+    ///
+    /// Saturates at `usize::MAX` rather than overflowing
+    pub(crate) fn image_data_len(&self) -> usize {
+        let width = self.width as usize;
+        let height = self.height as usize;
+
+        match self.interlace_method {
+            1 => interlacing::adam7_data_len(width, height, self.bits_per_pixel()),
+            _ => (interlacing::bytes_per_row(width, self.bits_per_pixel()) + 1)
+                .saturating_mul(height),
+        }
+    }
 }
 
 impl<'a> NamedChunk<'a> for IHDR {
@@ -130,7 +150,7 @@ impl<'a> Chunk<'a> for IHDR {
         )?)
     }
 
-    fn serialize(&self, buffer: &mut Vec<u8>) {
+    fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError> {
         buffer.extend_from_slice(&self.width.to_be_bytes());
         buffer.extend_from_slice(&self.height.to_be_bytes());
         buffer.push(self.bit_depth);
@@ -138,6 +158,7 @@ impl<'a> Chunk<'a> for IHDR {
         buffer.push(self.compression_type);
         buffer.push(self.filter_method);
         buffer.push(self.interlace_method);
+        Ok(())
     }
 }
 
@@ -149,7 +170,7 @@ pub trait Chunk<'a> {
     fn parse<T: Read + BufRead>(length: u32, buf: &mut T) -> Result<Self, PngDecodingError>
     where
         Self: Sized;
-    fn serialize(&self, buffer: &mut Vec<u8>);
+    fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError>;
 
     fn size_hint(&self) -> usize
     where
@@ -277,7 +298,7 @@ impl<'a> NamedChunk<'a> for PLTE {
 
 impl<'a> Chunk<'a> for PLTE {
     fn parse<T: Read + BufRead>(length: u32, buf: &mut T) -> Result<Self, PngDecodingError> {
-        if length % 3 != 0 {
+        if !length.is_multiple_of(3) {
             return Err(ChunkError::InvalidPLTELength.into());
         }
         let mut entries_buffer: Vec<u8> = vec![0; length as usize];
@@ -288,8 +309,19 @@ impl<'a> Chunk<'a> for PLTE {
         Ok(PLTE { entries })
     }
 
-    fn serialize(&self, _buffer: &mut Vec<u8>) {
-        todo!()
+    fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError> {
+        for entry in &self.entries {
+            // entries are only ever read from 8 bit samples
+            buffer.extend(entry.to_array().iter().map(|&sample| sample as u8));
+        }
+        Ok(())
+    }
+
+    fn size_hint(&self) -> usize
+    where
+        Self: Sized,
+    {
+        self.entries.len() * 3
     }
 }
 
@@ -328,26 +360,22 @@ impl<'a> Chunk<'a> for pHYs {
         })
     }
 
-    fn serialize(&self, buffer: &mut Vec<u8>) {
+    fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError> {
         buffer.extend_from_slice(&self.pixels_per_unit_x.to_be_bytes());
         buffer.extend_from_slice(&self.pixels_per_unit_y.to_be_bytes());
         buffer.push(self.unit as u8);
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
 #[repr(u8)]
 pub enum Unit {
+    #[default]
     Unknown = 0,
     Meters = 1,
 }
 
-impl std::default::Default for Unit {
-    fn default() -> Self {
-        Self::Unknown
-    }
-}
-
 impl Unit {
     pub fn from_u8(unit: u8) -> Result<Self, MetadataError> {
         match unit {
@@ -358,6 +386,11 @@ impl Unit {
     }
 }
 
+/// Decode Latin-1 text, in which every byte is the code point of its character
+pub(crate) fn decode_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| char::from(byte)).collect()
+}
+
 /// The tEXt chunk contains uncompressed, Latin-1 encoded textual information
 #[derive(Default, Debug, Clone, Hash, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
@@ -374,26 +407,24 @@ impl<'a> NamedChunk<'a> for tEXt {
 impl<'a> Chunk<'a> for tEXt {
     fn parse<T: Read + BufRead>(length: u32, buf: &mut T) -> Result<Self, PngDecodingError> {
         let mut keyword_buffer: Vec<u8> = Vec::new();
-        let keyword_len = buf.read_until(b'\0', &mut keyword_buffer)?;
-
-        let remaining_length = length - (keyword_len as u32);
+        buf.read_until(b'\0', &mut keyword_buffer)?;
 
-        let mut text_buffer: Vec<u8> = vec![0; remaining_length as usize];
-        buf.read_exact(&mut text_buffer)?;
+        let mut text_buffer: Vec<u8> = Vec::new();
+        buf.take(u64::from(length)).read_to_end(&mut text_buffer)?;
 
         // the null byte is included in `read_until()`
         keyword_buffer.pop();
 
-        // let keyword = if let Ok(k) = String::from_utf8(keyword_buffer) { k } else { continue };
-        let keyword = String::from_utf8(keyword_buffer)?;
-        let text = String::from_utf8(text_buffer)?;
+        let keyword = decode_latin1(&keyword_buffer);
+        let text = decode_latin1(&text_buffer);
 
         Ok(tEXt { keyword, text })
     }
 
-    fn serialize(&self, buffer: &mut Vec<u8>) {
+    fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError> {
         buffer.extend_from_slice(self.keyword.as_bytes());
         buffer.extend_from_slice(self.text.as_bytes());
+        Ok(())
     }
 
     fn size_hint(&self) -> usize
@@ -413,15 +444,24 @@ pub struct iTXt {
     pub compression_method: Option<u8>,
     pub language_tag: String,
     pub translated_keyword: String,
+    /// The text, after decompressing it if `compressed` is set
     pub text: String,
 }
 
 #[derive(Default, Debug, Clone, Copy, Hash, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub struct gAMA {
+    /// The gamma multiplied by 100000
     pub gamma: u32,
 }
 
+impl gAMA {
+    /// The gamma of the image, such as 0.45455 for a typical photograph
+    pub fn value(self) -> f64 {
+        f64::from(self.gamma) / 100_000.0
+    }
+}
+
 impl<'a> NamedChunk<'a> for gAMA {
     const NAME: [u8; 4] = *b"gAMA";
 }
@@ -437,8 +477,9 @@ impl<'a> Chunk<'a> for gAMA {
         Ok(gAMA { gamma })
     }
 
-    fn serialize(&self, buffer: &mut Vec<u8>) {
+    fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError> {
         buffer.extend_from_slice(&self.gamma.to_be_bytes());
+        Ok(())
     }
 }
 
@@ -513,7 +554,7 @@ impl<'a> Chunk<'a> for cHRM {
         })
     }
 
-    fn serialize(&self, buffer: &mut Vec<u8>) {
+    fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError> {
         buffer.extend_from_slice(&self.white_point_x.to_be_bytes());
         buffer.extend_from_slice(&self.white_point_y.to_be_bytes());
         buffer.extend_from_slice(&self.red_x.to_be_bytes());
@@ -522,6 +563,7 @@ impl<'a> Chunk<'a> for cHRM {
         buffer.extend_from_slice(&self.green_y.to_be_bytes());
         buffer.extend_from_slice(&self.blue_x.to_be_bytes());
         buffer.extend_from_slice(&self.blue_y.to_be_bytes());
+        Ok(())
     }
 }
 
@@ -559,10 +601,11 @@ impl<'a> Chunk<'a> for iCCP {
         })
     }
 
-    fn serialize(&self, buffer: &mut Vec<u8>) {
+    fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError> {
         buffer.extend_from_slice(&self.profile_name);
         buffer.push(self.compression_method);
         buffer.extend_from_slice(&self.compressed_profile);
+        Ok(())
     }
 
     fn size_hint(&self) -> usize
@@ -587,10 +630,7 @@ impl fmt::Debug for iCCP {
     }
 }
 
-#[derive(Default, Debug, Clone, Hash, PartialEq, Eq)]
-pub struct ICCProfile {
-    icc_profile: Vec<u8>,
-}
+pub use crate::icc::ICCProfile;
 
 /// Contains the number of significant bits.
 /// It is useful for scaling color precision
@@ -645,6 +685,9 @@ pub enum sRGB {
 }
 
 impl sRGB {
+    /// The gamma of the sRGB color space, which overrides any gAMA chunk
+    pub const GAMMA: gAMA = gAMA { gamma: 45455 };
+
     pub fn from_u8(val: u8) -> Result<sRGB, ChunkError> {
         match val {
             0 => Ok(sRGB::Perceptual),
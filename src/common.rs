@@ -46,36 +46,118 @@ impl ColorType {
     }
 }
 
+/// A rectangular region of an image, measured in pixels
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Bitmap {
     pub buffer: Vec<u8>,
     pub width: u32,
     pub height: u32,
-    /// Bytes per pixel
+    /// The color type of the pixels in `buffer`, which differs from the image's own
+    /// color type if it was decoded with `Transformations` that change it
+    pub color_type: ColorType,
+    /// Bytes per pixel, rounding up to 1
     pub bpp: usize,
+    /// Number of bits per sample, in `[1, 2, 4, 8, 16]`
+    pub bit_depth: u8,
+    /// Whether samples narrower than 8 bits are packed several to a byte, starting from
+    /// the most significant bit. If false, every sample occupies its own byte
+    pub packed: bool,
 }
 
 impl Bitmap {
+    /// A bitmap of `color_type` pixels with `bit_depth` bits per sample. Samples
+    /// narrower than 8 bits are expected to be packed
     pub fn new(
         width: u32,
         height: u32,
-        bpp: usize,
+        color_type: ColorType,
+        bit_depth: u8,
         buffer: Vec<u8>,
     ) -> Result<Bitmap, MetadataError> {
         // if rows.is_empty() || rows.len() > 2_usize.pow(31) {
         //     return Err(MetadataError::InvalidHeight { height: rows.len() });
         // }
 
+        let bits_per_pixel = usize::from(color_type.channels()) * usize::from(bit_depth);
         Ok(Bitmap {
             width,
             height,
-            bpp,
+            color_type,
+            bpp: std::cmp::max(1, bits_per_pixel / 8),
+            bit_depth,
+            packed: bit_depth < 8,
             buffer,
         })
     }
 
+    /// Number of bytes in each row of the buffer
+    pub fn stride(&self) -> usize {
+        if self.packed {
+            (self.width as usize * usize::from(self.bit_depth)).div_ceil(8)
+        } else {
+            self.width as usize * self.bpp
+        }
+    }
+
     pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
-        self.buffer.chunks_exact(self.width as usize * self.bpp)
+        self.buffer.chunks_exact(self.stride())
+    }
+
+    /// Iterate over the samples of a 16-bit image in native endianness.
+    ///
+    /// PNG stores 16-bit samples in big-endian order, which is how they appear in `buffer`.
+    /// Returns `None` if the bit depth is not 16
+    pub fn samples_u16(&self) -> Option<impl Iterator<Item = u16> + '_> {
+        if self.bit_depth != 16 {
+            return None;
+        }
+
+        Some(
+            self.buffer
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]])),
+        )
+    }
+
+    /// Iterate over the rows of a 16-bit image, each an iterator over its samples in
+    /// native endianness, without copying them.
+    ///
+    /// Returns `None` if the bit depth is not 16
+    pub fn rows_u16(&self) -> Option<impl Iterator<Item = impl Iterator<Item = u16> + '_> + '_> {
+        if self.bit_depth != 16 {
+            return None;
+        }
+
+        Some(self.rows().map(|row| {
+            row.chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        }))
+    }
+
+    /// Copy the samples of a 16-bit image into a new buffer in native endianness.
+    ///
+    /// Returns `None` if the bit depth is not 16
+    pub fn to_u16_vec(&self) -> Option<Vec<u16>> {
+        Some(self.samples_u16()?.collect())
     }
 
     pub fn flip(&mut self) {
@@ -0,0 +1,332 @@
+use crate::{
+    common::{Bitmap, Rect},
+    errors::PngDecodingError,
+    interlacing,
+    scanlines::ScanlineDecoder,
+    Png,
+};
+
+/// State that can be reused between images, so that decoding a sequence of images
+/// does not allocate for each one.
+///
+/// The inflater and the scratch scanlines are kept between calls, and only grow when
+/// an image needs larger ones
+#[derive(Debug, Default)]
+pub struct DecodeContext {
+    scanlines: Option<ScanlineDecoder>,
+}
+
+impl DecodeContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prepare the scanline decoder for a new image
+    fn start(&mut self, png: &Png) -> Result<&mut ScanlineDecoder, PngDecodingError> {
+        let scanlines = match self.scanlines.take() {
+            Some(mut scanlines) => {
+                scanlines.reset(&png.ihdr)?;
+                scanlines
+            }
+            None => ScanlineDecoder::new(&png.ihdr)?,
+        };
+        let scanlines = self.scanlines.insert(scanlines);
+
+        if png.idat.is_empty() {
+            return Err(PngDecodingError::ZeroLengthIDAT);
+        }
+
+        Ok(scanlines)
+    }
+
+    /// Decode `png` into the start of `out`, which must be at least
+    /// `png.output_buffer_size()` bytes long.
+    ///
+    /// The layout of the output is the same as the buffer returned by `Png::decode`
+    pub fn decode_into(&mut self, png: &Png, out: &mut [u8]) -> Result<(), PngDecodingError> {
+        let required = png.output_buffer_size();
+        if out.len() < required {
+            return Err(PngDecodingError::OutputBufferTooSmall {
+                required,
+                found: out.len(),
+            });
+        }
+
+        let scanlines = self.start(png)?;
+
+        let stride = png.bytes_per_row();
+        let bits_per_pixel = png.bits_per_pixel();
+        let out = &mut out[..required];
+
+        // rows of interlaced images are built up a few pixels at a time
+        if png.ihdr.interlace_method == 1 {
+            out.fill(0);
+        }
+
+        let mut input: &[u8] = &png.idat;
+
+        while !scanlines.is_finished() {
+            let (consumed, ready) = scanlines.decompress(input, true)?;
+            input = &input[consumed..];
+
+            if !ready {
+                continue;
+            }
+
+            let row = scanlines.take_row()?;
+            match row.pass {
+                Some(pass) => {
+                    let pass = usize::from(pass);
+                    let y = interlacing::image_y(pass, row.y as usize);
+                    interlacing::scatter_row(
+                        pass,
+                        row.data,
+                        row.width as usize,
+                        &mut out[(y * stride)..((y + 1) * stride)],
+                        bits_per_pixel,
+                    );
+                }
+                None => {
+                    let y = row.y as usize;
+                    out[(y * stride)..((y + 1) * stride)].copy_from_slice(row.data);
+                }
+            }
+        }
+
+        scanlines.check_end(input)
+    }
+
+    /// Decode only the pixels of `png` that lie within `rect`.
+    ///
+    /// Inflating stops as soon as the last row of the region has been unfiltered, and
+    /// columns outside of the region are discarded row by row, so only the region
+    /// itself is held in memory. The data after the region is not validated. Every
+    /// pass of an interlaced image covers the whole image, so all but the last pass
+    /// must still be inflated in full
+    pub fn decode_rect(&mut self, png: &Png, rect: Rect) -> Result<Bitmap, PngDecodingError> {
+        let (x0, y0) = (rect.x as usize, rect.y as usize);
+        let (x1, y1) = (x0 + rect.width as usize, y0 + rect.height as usize);
+
+        if rect.width == 0
+            || rect.height == 0
+            || x1 > png.width() as usize
+            || y1 > png.height() as usize
+        {
+            return Err(PngDecodingError::InvalidRegion(rect));
+        }
+
+        let scanlines = self.start(png)?;
+
+        let bits_per_pixel = png.bits_per_pixel();
+        let stride = interlacing::bytes_per_row(rect.width as usize, bits_per_pixel);
+        let mut out = vec![0; stride * rect.height as usize];
+
+        let mut input: &[u8] = &png.idat;
+
+        while !scanlines.is_finished() {
+            let (consumed, ready) = scanlines.decompress(input, true)?;
+            input = &input[consumed..];
+
+            if !ready {
+                continue;
+            }
+
+            let row = scanlines.take_row()?;
+            match row.pass {
+                Some(pass) => {
+                    let pass = usize::from(pass);
+                    let y = interlacing::image_y(pass, row.y as usize);
+
+                    if (y0..y1).contains(&y) {
+                        let out_row = &mut out[((y - y0) * stride)..((y - y0 + 1) * stride)];
+                        for pass_x in 0..row.width as usize {
+                            let x = interlacing::image_x(pass, pass_x);
+                            if (x0..x1).contains(&x) {
+                                interlacing::copy_pixel(
+                                    row.data,
+                                    pass_x,
+                                    out_row,
+                                    x - x0,
+                                    bits_per_pixel,
+                                );
+                            }
+                        }
+                    }
+
+                    // the last pass fills in every other row, so nothing more is needed
+                    // once it moves past the region
+                    if pass == 6 && y + 1 >= y1 {
+                        break;
+                    }
+                }
+                None => {
+                    let y = row.y as usize;
+
+                    if y >= y0 {
+                        let out_row = &mut out[((y - y0) * stride)..((y - y0 + 1) * stride)];
+                        if bits_per_pixel >= 8 {
+                            let bytes_per_pixel = bits_per_pixel / 8;
+                            out_row.copy_from_slice(
+                                &row.data[(x0 * bytes_per_pixel)..(x1 * bytes_per_pixel)],
+                            );
+                        } else {
+                            for x in x0..x1 {
+                                interlacing::copy_pixel(
+                                    row.data,
+                                    x,
+                                    out_row,
+                                    x - x0,
+                                    bits_per_pixel,
+                                );
+                            }
+                        }
+                    }
+
+                    if y + 1 >= y1 {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(Bitmap {
+            width: rect.width,
+            height: rect.height,
+            color_type: png.ihdr.color_type,
+            bpp: png.bpp(),
+            bit_depth: png.ihdr.bit_depth,
+            packed: png.ihdr.bit_depth < 8,
+            buffer: out,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chunks::IHDR, common::ColorType, decoder::PngDecoder, test_utils};
+
+    #[test]
+    fn reuse_context_between_images() {
+        let mut context = DecodeContext::new();
+
+        let first = PngDecoder::read(
+            test_utils::png(2, 2, 8, ColorType::Grayscale, &[], &[1, 2, 3, 4]).as_slice(),
+        )
+        .unwrap();
+        let second = PngDecoder::read(
+            test_utils::png(3, 1, 2, ColorType::Grayscale, &[], &[0b1001_1100]).as_slice(),
+        )
+        .unwrap();
+
+        let mut out = [0xff; 8];
+        context.decode_into(&first, &mut out).unwrap();
+        assert_eq!(out, [1, 2, 3, 4, 0xff, 0xff, 0xff, 0xff]);
+
+        assert_eq!(second.output_buffer_size(), 1);
+        context.decode_into(&second, &mut out).unwrap();
+        assert_eq!(out[0], 0b1001_1100);
+
+        assert!(matches!(
+            context.decode_into(&first, &mut out[..3]),
+            Err(PngDecodingError::OutputBufferTooSmall {
+                required: 4,
+                found: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn decode_interlaced_into() {
+        let (width, height) = (5, 6);
+        let image: Vec<u8> = (0..(width * height * 2)).map(|i| i as u8).collect();
+
+        let png = Png {
+            ihdr: IHDR::new(
+                width as u32,
+                height as u32,
+                8,
+                ColorType::GrayscaleAlpha,
+                0,
+                0,
+                1,
+            )
+            .unwrap(),
+            idat: test_utils::zlib(&test_utils::encode_adam7(&image, width, height, 16)),
+            ..Default::default()
+        };
+
+        let mut out = vec![0; png.output_buffer_size()];
+        png.decode_into(&mut out).unwrap();
+        assert_eq!(out, image);
+        assert_eq!(out, png.decode().unwrap().buffer);
+    }
+
+    #[test]
+    fn decode_rect_crops_rows_and_columns() {
+        let (width, height) = (6, 5);
+        let image: Vec<u8> = (0..(width * height)).map(|i| i as u8).collect();
+        let rect = Rect::new(1, 2, 3, 2);
+        let expected = vec![13, 14, 15, 19, 20, 21];
+
+        let png = PngDecoder::read(
+            test_utils::png(
+                width as u32,
+                height as u32,
+                8,
+                ColorType::Grayscale,
+                &[],
+                &image,
+            )
+            .as_slice(),
+        )
+        .unwrap();
+        assert_eq!(png.decode_rect(rect).unwrap().buffer, expected);
+        assert_eq!(
+            png.decode_rows(1..3).unwrap().buffer,
+            &image[width..(3 * width)]
+        );
+
+        let interlaced = Png {
+            ihdr: IHDR::new(
+                width as u32,
+                height as u32,
+                8,
+                ColorType::Grayscale,
+                0,
+                0,
+                1,
+            )
+            .unwrap(),
+            idat: test_utils::zlib(&test_utils::encode_adam7(&image, width, height, 8)),
+            ..Default::default()
+        };
+        assert_eq!(interlaced.decode_rect(rect).unwrap().buffer, expected);
+
+        assert!(matches!(
+            png.decode_rect(Rect::new(4, 0, 3, 1)),
+            Err(PngDecodingError::InvalidRegion(_))
+        ));
+    }
+
+    #[test]
+    fn decode_rect_of_packed_samples() {
+        // a 1-bit checkerboard
+        let png = PngDecoder::read(
+            test_utils::png(
+                4,
+                2,
+                1,
+                ColorType::Grayscale,
+                &[],
+                &[0b1010_0000, 0b0101_0000],
+            )
+            .as_slice(),
+        )
+        .unwrap();
+
+        let region = png.decode_rect(Rect::new(1, 0, 3, 2)).unwrap();
+        assert!(region.packed);
+        assert_eq!(region.buffer, vec![0b0100_0000, 0b1010_0000]);
+    }
+}
@@ -0,0 +1,288 @@
+//! Support for chunk types defined outside of this crate, such as private chunks
+//! embedded by an application
+
+use std::{
+    any::{Any, TypeId},
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+use crate::{
+    chunks::NamedChunk,
+    errors::{PngDecodingError, PngEncodingError},
+};
+
+/// A chunk type defined outside of this crate.
+///
+/// This is implemented for every type that implements `NamedChunk`, along with the
+/// traits needed to store it in a `Png`. Register a type with
+/// `DecoderOptions::chunk_handler` to have chunks of type `NAME` parsed into it while
+/// decoding, and add it to `Png::custom_chunks` to have it written out on save
+pub trait CustomChunk:
+    NamedChunk<'static> + Any + Clone + PartialEq + fmt::Debug + Send + Sync
+{
+}
+
+impl<T> CustomChunk for T where
+    T: NamedChunk<'static> + Any + Clone + PartialEq + fmt::Debug + Send + Sync
+{
+}
+
+/// A `CustomChunk` with its type erased, so that chunks of different types can be
+/// stored together
+pub(crate) trait ErasedChunk: fmt::Debug + Send + Sync {
+    fn chunk_type(&self) -> [u8; 4];
+    fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn clone_box(&self) -> Box<dyn ErasedChunk>;
+    fn eq_dyn(&self, other: &dyn ErasedChunk) -> bool;
+}
+
+impl<T: CustomChunk> ErasedChunk for T {
+    fn chunk_type(&self) -> [u8; 4] {
+        T::NAME
+    }
+
+    fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError> {
+        crate::chunks::Chunk::serialize(self, buffer)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn ErasedChunk> {
+        Box::new(self.clone())
+    }
+
+    fn eq_dyn(&self, other: &dyn ErasedChunk) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
+}
+
+type ParseFn = fn(&[u8]) -> Result<Box<dyn ErasedChunk>, PngDecodingError>;
+
+/// Parses chunks of one type into a `CustomChunk`
+#[derive(Clone, Copy)]
+pub(crate) struct ChunkHandler {
+    pub chunk_type: [u8; 4],
+    type_id: TypeId,
+    parse: ParseFn,
+}
+
+fn parse_custom<T: CustomChunk>(data: &[u8]) -> Result<Box<dyn ErasedChunk>, PngDecodingError> {
+    let mut buf = data;
+    Ok(Box::new(T::parse(data.len() as u32, &mut buf)?))
+}
+
+impl ChunkHandler {
+    pub fn new<T: CustomChunk>() -> Self {
+        ChunkHandler {
+            chunk_type: T::NAME,
+            type_id: TypeId::of::<T>(),
+            parse: parse_custom::<T>,
+        }
+    }
+
+    pub fn parse(&self, data: &[u8], chunks: &mut CustomChunks) -> Result<(), PngDecodingError> {
+        chunks.chunks.push((self.parse)(data)?);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ChunkHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ChunkHandler({})",
+            String::from_utf8_lossy(&self.chunk_type)
+        )
+    }
+}
+
+// handlers for the same type always parse in the same way
+impl PartialEq for ChunkHandler {
+    fn eq(&self, other: &Self) -> bool {
+        self.chunk_type == other.chunk_type && self.type_id == other.type_id
+    }
+}
+
+impl Eq for ChunkHandler {}
+
+impl Hash for ChunkHandler {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.chunk_type.hash(state);
+        self.type_id.hash(state);
+    }
+}
+
+/// Chunks parsed by the handlers registered with `DecoderOptions::chunk_handler`, or
+/// added to be written out when the image is saved
+#[derive(Default)]
+pub struct CustomChunks {
+    chunks: Vec<Box<dyn ErasedChunk>>,
+}
+
+impl CustomChunks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The first chunk of type `T`
+    pub fn get<T: CustomChunk>(&self) -> Option<&T> {
+        self.chunks
+            .iter()
+            .find_map(|chunk| chunk.as_any().downcast_ref())
+    }
+
+    pub fn get_mut<T: CustomChunk>(&mut self) -> Option<&mut T> {
+        self.chunks
+            .iter_mut()
+            .find_map(|chunk| chunk.as_any_mut().downcast_mut())
+    }
+
+    /// Every chunk of type `T`, in the order they appear in the file
+    pub fn get_all<T: CustomChunk>(&self) -> impl Iterator<Item = &T> {
+        self.chunks
+            .iter()
+            .filter_map(|chunk| chunk.as_any().downcast_ref())
+    }
+
+    pub fn push<T: CustomChunk>(&mut self, chunk: T) {
+        self.chunks.push(Box::new(chunk));
+    }
+
+    /// Remove every chunk of type `T`
+    pub fn remove<T: CustomChunk>(&mut self) {
+        self.chunks.retain(|chunk| !chunk.as_any().is::<T>());
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &dyn ErasedChunk> {
+        self.chunks.iter().map(|chunk| chunk.as_ref())
+    }
+}
+
+impl Clone for CustomChunks {
+    fn clone(&self) -> Self {
+        CustomChunks {
+            chunks: self.chunks.iter().map(|chunk| chunk.clone_box()).collect(),
+        }
+    }
+}
+
+impl PartialEq for CustomChunks {
+    fn eq(&self, other: &Self) -> bool {
+        self.chunks.len() == other.chunks.len()
+            && self
+                .chunks
+                .iter()
+                .zip(&other.chunks)
+                .all(|(chunk, other)| chunk.eq_dyn(other.as_ref()))
+    }
+}
+
+impl Eq for CustomChunks {}
+
+// only the types are hashed, which is consistent with `eq`
+impl Hash for CustomChunks {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for chunk in &self.chunks {
+            chunk.chunk_type().hash(state);
+            chunk.as_any().type_id().hash(state);
+        }
+    }
+}
+
+impl fmt::Debug for CustomChunks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(&self.chunks).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufWriter, Read};
+
+    use super::*;
+    use crate::{
+        chunks::Chunk, common::ColorType, decoder::PngDecoder, test_utils, DecoderOptions,
+    };
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Calibration {
+        gain: u16,
+        label: String,
+    }
+
+    impl NamedChunk<'static> for Calibration {
+        const NAME: [u8; 4] = *b"caLb";
+    }
+
+    impl Chunk<'static> for Calibration {
+        fn parse<T: Read + BufRead>(_length: u32, buf: &mut T) -> Result<Self, PngDecodingError> {
+            let mut gain = [0; 2];
+            buf.read_exact(&mut gain)?;
+            let mut label = String::new();
+            buf.read_to_string(&mut label)?;
+            Ok(Calibration {
+                gain: u16::from_be_bytes(gain),
+                label,
+            })
+        }
+
+        fn serialize(&self, buffer: &mut Vec<u8>) -> Result<(), PngEncodingError> {
+            buffer.extend_from_slice(&self.gain.to_be_bytes());
+            buffer.extend_from_slice(self.label.as_bytes());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_chunks_round_trip() {
+        let file = test_utils::png(
+            1,
+            1,
+            8,
+            ColorType::Grayscale,
+            &[test_utils::chunk(b"caLb", b"\x01\x02sensor a")],
+            &[5],
+        );
+        let options = DecoderOptions::new().chunk_handler::<Calibration>();
+
+        let without_handler = PngDecoder::read(file.as_slice()).unwrap();
+        assert!(without_handler.custom_chunks.is_empty());
+        assert_eq!(without_handler.unrecognized_chunks.len(), 1);
+
+        let mut png = PngDecoder::read_with_options(file.as_slice(), &options).unwrap();
+        assert!(png.unrecognized_chunks.is_empty());
+        let calibration = png.custom_chunks.get_mut::<Calibration>().unwrap();
+        assert_eq!(calibration.gain, 0x0102);
+        assert_eq!(calibration.label, "sensor a");
+        calibration.label = "sensor b".to_owned();
+
+        let mut encoded = Vec::new();
+        png.write(&mut BufWriter::new(&mut encoded)).unwrap();
+        let decoded = PngDecoder::read_with_options(encoded.as_slice(), &options).unwrap();
+        assert_eq!(
+            decoded.custom_chunks.get(),
+            Some(&Calibration {
+                gain: 0x0102,
+                label: "sensor b".to_owned()
+            })
+        );
+        assert_eq!(decoded.custom_chunks, png.custom_chunks);
+    }
+}
@@ -1,334 +1,1573 @@
+use std::io::{self, BufRead, Read};
+
+use crc32fast::Hasher;
+use flate2::bufread::ZlibDecoder;
+
 use crate::{
     chunks::{
-        bKGD, cHRM, gAMA, iCCP, iTXt, pHYs, sBIT, sRGB, tEXt, tRNS, AncillaryChunks, Chunk,
-        UnrecognizedChunk, IHDR, PLTE,
+        bKGD, cHRM, decode_latin1, gAMA, iCCP, iTXt, pHYs, sBIT, sRGB, tEXt, tRNS, AncillaryChunks,
+        Chunk, UnrecognizedChunk, IHDR, PLTE,
     },
     common::{get_bit_at, ColorType, HEADER, IEND},
-    errors::{ChunkError, PngDecodingError},
-    interlacing, Png,
+    custom::{ChunkHandler, CustomChunk, CustomChunks},
+    errors::{
+        ChunkError, DecodeWarning, ErrorLocation, LimitError, MetadataError, PngDecodingError,
+    },
+    Png,
 };
 
+/// How the CRC at the end of each chunk is checked
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
+pub enum CrcCheck {
+    /// Fail with `ChunkError::CrcMismatch`
+    #[default]
+    Error,
+    /// Record a `DecodeWarning` and continue decoding
+    Warn,
+    /// Do not compute CRCs at all. Useful for trusted input
+    Skip,
+}
+
+/// How chunks that break the ordering and multiplicity rules of the specification,
+/// or that are not allowed for the image's color type, are handled
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
+pub enum ChunkOrder {
+    /// Fail with the `ChunkError` describing the violation
+    #[default]
+    Strict,
+    /// Record a `DecodeWarning` and ignore the out of place chunk. Image data is
+    /// never ignored, so IDAT chunks separated by other chunks are joined
+    Lenient,
+}
+
+/// What the decoder does with an ancillary chunk
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
+pub enum ChunkPolicy {
+    /// Parse the chunk and store it in the `Png`
+    #[default]
+    Keep,
+    /// Skip over the chunk without storing or parsing it
+    Ignore,
+    /// Fail with `ChunkError::RejectedChunk`
+    Reject,
+}
+
+/// Ancillary chunks that the decoder parses. Any other ancillary chunk is unknown,
+/// and is kept as an `UnrecognizedChunk`
+const KNOWN_CHUNKS: [&[u8; 4]; 10] = [
+    b"tRNS", b"pHYs", b"tEXt", b"iTXt", b"bKGD", b"gAMA", b"cHRM", b"iCCP", b"sBIT", b"sRGB",
+];
+
+/// Chunks that may appear at most once in a file
+const UNIQUE_CHUNKS: [&[u8; 4]; 14] = [
+    b"IHDR", b"PLTE", b"IEND", b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"bKGD", b"hIST",
+    b"tRNS", b"pHYs", b"tIME", b"eXIf",
+];
+
+/// Upper bounds on the resources a single file may claim while it is decoded.
+///
+/// The defaults are generous enough for any reasonable image, while stopping a small,
+/// hostile file from allocating gigabytes of memory
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
-pub struct PngDecoder;
+pub struct DecoderLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Maximum value of `width * height`
+    pub max_pixels: u64,
+    /// Maximum length of the data of any single chunk
+    pub max_chunk_size: u32,
+    /// Maximum combined length of all chunks other than IHDR, IDAT and IEND
+    pub max_metadata_bytes: u64,
+    /// Maximum length of the image data once inflated, including filter type bytes
+    pub max_decompressed_bytes: u64,
+}
 
-impl PngDecoder {
-    pub fn read<T: std::io::BufRead + std::io::Read>(mut f: T) -> Result<Png, PngDecodingError> {
-        let mut header = [0u8; 8];
-        let mut ihdr: IHDR = Default::default();
-        let mut unrecognized_chunks: Vec<UnrecognizedChunk> = Vec::new();
-        let mut idat: Vec<u8> = Vec::new();
-        let mut ancillary_chunks: AncillaryChunks = AncillaryChunks::new();
-        let mut plte: Option<PLTE> = None;
-
-        f.read_exact(&mut header)?;
-        if header != HEADER {
-            return Err(PngDecodingError::InvalidHeader {
-                found: header,
-                expected: HEADER,
+impl DecoderLimits {
+    /// No limits beyond those imposed by the PNG format itself
+    pub const fn unlimited() -> Self {
+        DecoderLimits {
+            max_width: u32::MAX,
+            max_height: u32::MAX,
+            max_pixels: u64::MAX,
+            max_chunk_size: u32::MAX,
+            max_metadata_bytes: u64::MAX,
+            max_decompressed_bytes: u64::MAX,
+        }
+    }
+
+    fn check_ihdr(&self, ihdr: &IHDR) -> Result<(), LimitError> {
+        if ihdr.width > self.max_width {
+            return Err(LimitError::WidthTooLarge {
+                width: ihdr.width,
+                max: self.max_width,
             });
         }
 
-        loop {
-            let mut length_buffer: [u8; 4] = [0u8; 4];
-            f.read_exact(&mut length_buffer)?;
-            let length: u32 = u32::from_be_bytes(length_buffer);
+        if ihdr.height > self.max_height {
+            return Err(LimitError::HeightTooLarge {
+                height: ihdr.height,
+                max: self.max_height,
+            });
+        }
 
-            let mut chunk_type: [u8; 4] = [0; 4];
-            f.read_exact(&mut chunk_type)?;
+        let pixels = u64::from(ihdr.width) * u64::from(ihdr.height);
+        if pixels > self.max_pixels {
+            return Err(LimitError::TooManyPixels {
+                pixels,
+                max: self.max_pixels,
+            });
+        }
 
-            match &chunk_type {
-                // Critical
-                b"IHDR" => ihdr = IHDR::parse(length, &mut f)?,
-                b"PLTE" => {
-                    match ihdr.color_type {
-                        ColorType::Indexed | ColorType::RGB | ColorType::RGBA => {}
-                        ColorType::Grayscale | ColorType::GrayscaleAlpha => {
-                            return Err(ChunkError::UnexpectedPLTEChunk.into())
-                        }
-                    }
+        let length = ihdr.image_data_len() as u64;
+        if length > self.max_decompressed_bytes {
+            return Err(LimitError::DecompressedDataTooLarge {
+                length,
+                max: self.max_decompressed_bytes,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for DecoderLimits {
+    fn default() -> Self {
+        DecoderLimits {
+            max_width: 1 << 24,
+            max_height: 1 << 24,
+            max_pixels: 1 << 28,
+            max_chunk_size: 1 << 30,
+            max_metadata_bytes: 1 << 26,
+            max_decompressed_bytes: 1 << 31,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Default)]
+pub struct DecoderOptions {
+    crc_check: CrcCheck,
+    chunk_order: ChunkOrder,
+    limits: DecoderLimits,
+    chunk_policies: Vec<([u8; 4], ChunkPolicy)>,
+    unknown_chunks: ChunkPolicy,
+    chunk_handlers: Vec<ChunkHandler>,
+}
 
-                    plte = Some(PLTE::parse(length, &mut f)?);
+impl DecoderOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn crc_check(mut self, crc_check: CrcCheck) -> Self {
+        self.crc_check = crc_check;
+        self
+    }
+
+    pub fn chunk_order(mut self, chunk_order: ChunkOrder) -> Self {
+        self.chunk_order = chunk_order;
+        self
+    }
+
+    pub fn limits(mut self, limits: DecoderLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Set what happens to ancillary chunks of the given type. Critical chunks are
+    /// always read, whatever their policy
+    pub fn chunk_policy(mut self, chunk_type: [u8; 4], policy: ChunkPolicy) -> Self {
+        self.chunk_policies.retain(|(ty, _)| ty != &chunk_type);
+        self.chunk_policies.push((chunk_type, policy));
+        self
+    }
+
+    /// Set what happens to ancillary chunks that the decoder does not parse, other
+    /// than those given a policy of their own with `chunk_policy`
+    pub fn unknown_chunks(mut self, policy: ChunkPolicy) -> Self {
+        self.unknown_chunks = policy;
+        self
+    }
+
+    /// Parse chunks of type `T::NAME` into a `T` while decoding. The parsed chunks are
+    /// stored in `Png::custom_chunks`.
+    ///
+    /// Handlers are only used for chunk types that the decoder does not parse itself.
+    /// A handler for a critical chunk type allows files that contain it to be decoded
+    pub fn chunk_handler<T: CustomChunk>(mut self) -> Self {
+        let handler = ChunkHandler::new::<T>();
+        self.chunk_handlers
+            .retain(|existing| existing.chunk_type != handler.chunk_type);
+        self.chunk_handlers.push(handler);
+        self
+    }
+
+    fn handler_for(&self, chunk_type: [u8; 4]) -> Option<ChunkHandler> {
+        self.chunk_handlers
+            .iter()
+            .find(|handler| handler.chunk_type == chunk_type)
+            .copied()
+    }
+
+    /// Whether chunks of the given type were given a policy with `chunk_policy`
+    fn has_policy(&self, chunk_type: [u8; 4]) -> bool {
+        self.chunk_policies.iter().any(|(ty, _)| ty == &chunk_type)
+    }
+
+    /// The policy that applies to chunks of the given type
+    pub(crate) fn policy_for(&self, chunk_type: [u8; 4]) -> ChunkPolicy {
+        let is_critical = !get_bit_at(chunk_type[0], 5);
+        if is_critical {
+            return ChunkPolicy::Keep;
+        }
+
+        match self.chunk_policies.iter().find(|(ty, _)| ty == &chunk_type) {
+            Some(&(_, policy)) => policy,
+            None if KNOWN_CHUNKS.contains(&&chunk_type) => ChunkPolicy::Keep,
+            None if self.handler_for(chunk_type).is_some() => ChunkPolicy::Keep,
+            None => self.unknown_chunks,
+        }
+    }
+
+    /// These options, relaxed so that every problem they can tolerate becomes a warning
+    pub(crate) fn relaxed(&self) -> Self {
+        let mut options = self.clone();
+        if options.crc_check == CrcCheck::Error {
+            options.crc_check = CrcCheck::Warn;
+        }
+        options.chunk_order = ChunkOrder::Lenient;
+        options
+    }
+}
+
+/// The CRC of a chunk, computed over its type and data but not its length
+fn chunk_crc(chunk_type: &[u8; 4], data: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Read and check the 8 byte PNG signature
+pub(crate) fn read_header<T: Read>(f: &mut T) -> Result<(), PngDecodingError> {
+    let mut header = [0u8; 8];
+    f.read_exact(&mut header)?;
+    check_header(header)
+}
+
+pub(crate) fn check_header(header: [u8; 8]) -> Result<(), PngDecodingError> {
+    if header != HEADER {
+        return Err(PngDecodingError::InvalidHeader {
+            found: header,
+            expected: HEADER,
+        });
+    }
+    Ok(())
+}
+
+/// Read the length and type that begin every chunk
+pub(crate) fn read_chunk_header<T: Read>(f: &mut T) -> Result<(u32, [u8; 4]), PngDecodingError> {
+    let mut length_buffer: [u8; 4] = [0u8; 4];
+    f.read_exact(&mut length_buffer)?;
+    let length: u32 = u32::from_be_bytes(length_buffer);
+
+    let mut chunk_type: [u8; 4] = [0; 4];
+    f.read_exact(&mut chunk_type)?;
+
+    Ok((length, chunk_type))
+}
+
+/// Read the CRC that ends every chunk
+pub(crate) fn read_crc<T: Read>(f: &mut T) -> Result<u32, PngDecodingError> {
+    let mut crc_buffer = [0u8; 4];
+    f.read_exact(&mut crc_buffer)?;
+    Ok(u32::from_be_bytes(crc_buffer))
+}
+
+/// Read the data and CRC of a chunk whose header has just been read, checking the CRC
+pub(crate) fn read_chunk_data<T: Read>(
+    f: &mut T,
+    state: &mut DecoderState,
+    length: u32,
+    chunk_type: [u8; 4],
+) -> Result<(Vec<u8>, u32), PngDecodingError> {
+    // only allocate as much as is actually present, rather than trusting `length`
+    let mut data: Vec<u8> = Vec::new();
+    f.take(u64::from(length)).read_to_end(&mut data)?;
+    if data.len() != length as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    let crc = read_crc(f)?;
+    state.check_chunk_crc(chunk_type, &data, crc)?;
+
+    Ok((data, crc))
+}
+
+/// Read past the data and CRC of a chunk whose header has just been read, checking
+/// the CRC without storing the data
+pub(crate) fn skip_chunk_data<T: Read>(
+    f: &mut T,
+    state: &mut DecoderState,
+    length: u32,
+    chunk_type: [u8; 4],
+) -> Result<u32, PngDecodingError> {
+    let mut hasher = state.chunk_hasher(chunk_type);
+
+    let data = &mut f.take(u64::from(length));
+    let skipped = match hasher.as_mut() {
+        Some(hasher) => io::copy(data, &mut HashWriter(hasher))?,
+        None => io::copy(data, &mut io::sink())?,
+    };
+    if skipped != u64::from(length) {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    let crc = read_crc(f)?;
+    state.check_skipped_crc(chunk_type, hasher, crc)?;
+
+    Ok(crc)
+}
+
+/// Feeds everything written to it into a CRC
+pub(crate) struct HashWriter<'a>(pub &'a mut Hasher);
+
+impl io::Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// IEND chunks are always empty
+pub(crate) fn check_iend(length: u32, crc: u32) -> Result<(), PngDecodingError> {
+    if length != 0 {
+        return Err(PngDecodingError::InvalidIENDChunk {
+            found: (length, crc.to_be_bytes()),
+            expected: IEND,
+        });
+    }
+    Ok(())
+}
+
+/// Everything learned from the chunks of a file other than its image data.
+///
+/// This is shared by each of the decoders, which differ only in how they get
+/// hold of the bytes of each chunk
+#[derive(Debug, Clone)]
+pub(crate) struct DecoderState {
+    pub options: DecoderOptions,
+    pub ihdr: IHDR,
+    pub plte: Option<PLTE>,
+    pub unrecognized_chunks: Vec<UnrecognizedChunk>,
+    pub ancillary_chunks: AncillaryChunks,
+    pub custom_chunks: CustomChunks,
+    pub warnings: Vec<DecodeWarning>,
+    metadata_bytes: u64,
+    /// Each distinct type of chunk that has been accepted so far
+    seen_chunks: Vec<[u8; 4]>,
+    previous_chunk: Option<[u8; 4]>,
+    /// The chunk currently being read, for reporting errors
+    location: ErrorLocation,
+    /// The byte offset of the chunk after the current one
+    next_offset: u64,
+}
+
+impl DecoderState {
+    pub fn new(options: &DecoderOptions) -> Self {
+        DecoderState {
+            options: options.clone(),
+            ihdr: Default::default(),
+            plte: None,
+            unrecognized_chunks: Vec::new(),
+            ancillary_chunks: AncillaryChunks::new(),
+            custom_chunks: CustomChunks::new(),
+            warnings: Vec::new(),
+            metadata_bytes: 0,
+            seen_chunks: Vec::new(),
+            previous_chunk: None,
+            location: ErrorLocation {
+                offset: 0,
+                chunk_index: None,
+                chunk_type: None,
+            },
+            next_offset: HEADER.len() as u64,
+        }
+    }
+
+    /// Move on to the next chunk, before reading its header
+    pub fn start_chunk(&mut self) {
+        self.location = ErrorLocation {
+            offset: self.next_offset,
+            chunk_index: Some(self.location.chunk_index.map_or(0, |index| index + 1)),
+            chunk_type: None,
+        };
+    }
+
+    /// Attach the location of the current chunk to an error
+    pub fn locate(&self, error: PngDecodingError) -> PngDecodingError {
+        match error {
+            PngDecodingError::Located { .. } => error,
+            error => PngDecodingError::Located {
+                location: self.location,
+                error: Box::new(error),
+            },
+        }
+    }
+
+    /// Run `read`, attaching the location at which it failed to any error
+    pub fn locate_errors<R>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<R, PngDecodingError>,
+    ) -> Result<R, PngDecodingError> {
+        read(self).map_err(|err| self.locate(err))
+    }
+
+    /// Check a chunk's claimed length against the configured limits, before any
+    /// of its data is read
+    pub fn check_length(&mut self, length: u32, chunk_type: [u8; 4]) -> Result<(), LimitError> {
+        self.location.chunk_type = Some(chunk_type);
+        self.next_offset = self.location.offset + 12 + u64::from(length);
+
+        let limits = &self.options.limits;
+
+        if length > limits.max_chunk_size {
+            return Err(LimitError::ChunkTooLarge {
+                chunk_type,
+                length,
+                max: limits.max_chunk_size,
+            });
+        }
+
+        // ignored chunks are never stored, so do not count towards the limit
+        if !matches!(&chunk_type, b"IHDR" | b"IDAT" | b"IEND")
+            && self.options.policy_for(chunk_type) == ChunkPolicy::Keep
+        {
+            self.metadata_bytes += u64::from(length);
+            if self.metadata_bytes > limits.max_metadata_bytes {
+                return Err(LimitError::TooMuchMetadata {
+                    total: self.metadata_bytes,
+                    max: limits.max_metadata_bytes,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that a chunk is allowed to appear at this point in the file, and that its
+    /// type is not rejected by the options, before any of its data is read. This must
+    /// be called for every chunk, including IEND.
+    ///
+    /// Returns whether the chunk should be processed, which is false for ignored chunks
+    /// and for out of place chunks in lenient mode. The data of chunks that are not
+    /// processed should be skipped with `skip_chunk_data`. IDAT and IEND chunks are
+    /// always processed, and a file without an IHDR chunk is an error in either mode
+    pub fn check_order(&mut self, chunk_type: [u8; 4]) -> Result<bool, PngDecodingError> {
+        let previous_chunk = self.previous_chunk.replace(chunk_type);
+
+        match self.options.policy_for(chunk_type) {
+            ChunkPolicy::Keep => {}
+            ChunkPolicy::Ignore => return Ok(false),
+            ChunkPolicy::Reject => return Err(ChunkError::RejectedChunk(chunk_type).into()),
+        }
+
+        let accepted = match self.order_violation(chunk_type, previous_chunk) {
+            None => true,
+            Some(err) => match self.options.chunk_order {
+                ChunkOrder::Strict => return Err(err.into()),
+                // there is nothing to decode without an IHDR chunk
+                ChunkOrder::Lenient if err == ChunkError::IHDRChunkNotFound => {
+                    return Err(err.into())
                 }
-                b"tRNS" => match ihdr.color_type {
-                    ColorType::Grayscale => {
-                        let mut grayscale_buffer = [0u8; 2];
-                        f.read_exact(&mut grayscale_buffer)?;
-                        let grayscale = u16::from_be_bytes(grayscale_buffer);
-                        ancillary_chunks.tRNS = Some(tRNS::Grayscale { grayscale });
-                    }
-                    ColorType::RGB => {
-                        let mut red_buffer = [0u8; 2];
-                        let mut green_buffer = [0u8; 2];
-                        let mut blue_buffer = [0u8; 2];
+                ChunkOrder::Lenient => {
+                    self.warnings.push(DecodeWarning::ChunkError(err));
+                    matches!(&chunk_type, b"IDAT" | b"IEND")
+                }
+            },
+        };
+
+        // later checks depend on every chunk that was processed, even out of place ones
+        if accepted && !self.seen_chunks.contains(&chunk_type) {
+            self.seen_chunks.push(chunk_type);
+        }
 
-                        f.read_exact(&mut red_buffer)?;
-                        f.read_exact(&mut green_buffer)?;
-                        f.read_exact(&mut blue_buffer)?;
+        Ok(accepted)
+    }
 
-                        let red = u16::from_be_bytes(red_buffer);
-                        let green = u16::from_be_bytes(green_buffer);
-                        let blue = u16::from_be_bytes(blue_buffer);
+    fn order_violation(
+        &self,
+        chunk_type: [u8; 4],
+        previous_chunk: Option<[u8; 4]>,
+    ) -> Option<ChunkError> {
+        let seen = |chunk_type: &[u8; 4]| self.seen_chunks.contains(chunk_type);
+        let indexed = self.ihdr.color_type == ColorType::Indexed;
 
-                        ancillary_chunks.tRNS = Some(tRNS::RGB { red, green, blue });
-                    }
-                    ColorType::Indexed => {
-                        let mut entries: Vec<u8> = vec![0; length as usize];
-                        f.read_exact(&mut entries)?;
-                        ancillary_chunks.tRNS = Some(tRNS::Indexed { entries });
+        if &chunk_type == b"IEND" {
+            return if !seen(b"IHDR") {
+                Some(ChunkError::IHDRChunkNotFound)
+            } else if !seen(b"IDAT") {
+                Some(ChunkError::IDATChunkNotFound)
+            } else {
+                None
+            };
+        }
+
+        if !seen(b"IHDR") && &chunk_type != b"IHDR" {
+            return Some(ChunkError::ChunkBeforeIHDR(chunk_type));
+        }
+
+        if UNIQUE_CHUNKS.contains(&&chunk_type) && seen(&chunk_type) {
+            return Some(ChunkError::DuplicateChunk(chunk_type));
+        }
+
+        let idat_seen = seen(b"IDAT");
+
+        match &chunk_type {
+            b"IDAT" if idat_seen && previous_chunk != Some(*b"IDAT") => {
+                Some(ChunkError::NonConsecutiveIDAT)
+            }
+            b"IDAT" if indexed && !idat_seen && !seen(b"PLTE") => {
+                Some(ChunkError::PLTEChunkNotFound)
+            }
+            b"PLTE" | b"cHRM" | b"gAMA" | b"iCCP" | b"sBIT" | b"sRGB" | b"bKGD" | b"hIST"
+            | b"tRNS" | b"pHYs" | b"sPLT"
+                if idat_seen =>
+            {
+                Some(ChunkError::ChunkAfterIDAT(chunk_type))
+            }
+            b"PLTE" => [b"bKGD", b"hIST", b"tRNS"]
+                .iter()
+                .find(|&&chunk_type| seen(chunk_type))
+                .map(|&&chunk_type| ChunkError::ChunkBeforePLTE(chunk_type)),
+            b"cHRM" | b"gAMA" | b"iCCP" | b"sBIT" | b"sRGB" if seen(b"PLTE") => {
+                Some(ChunkError::ChunkAfterPLTE(chunk_type))
+            }
+            b"bKGD" | b"hIST" | b"tRNS" if indexed && !seen(b"PLTE") => {
+                Some(ChunkError::ChunkBeforePLTE(chunk_type))
+            }
+            b"tRNS"
+                if matches!(
+                    self.ihdr.color_type,
+                    ColorType::RGBA | ColorType::GrayscaleAlpha
+                ) =>
+            {
+                Some(ChunkError::UnexpectedtRNSChunk)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the CRC of each chunk needs to be computed
+    pub fn checks_crc(&self) -> bool {
+        self.options.crc_check != CrcCheck::Skip
+    }
+
+    /// Compare the CRC computed from a chunk with the one stored after it
+    pub fn check_crc(
+        &mut self,
+        chunk_type: [u8; 4],
+        expected: u32,
+        found: u32,
+    ) -> Result<(), PngDecodingError> {
+        if !self.checks_crc() || expected == found {
+            return Ok(());
+        }
+
+        let err = ChunkError::CrcMismatch {
+            chunk_type,
+            expected,
+            found,
+        };
+
+        if self.options.crc_check == CrcCheck::Warn {
+            self.warnings.push(DecodeWarning::ChunkError(err));
+            Ok(())
+        } else {
+            Err(err.into())
+        }
+    }
+
+    /// Check the CRC of a chunk whose data was read in full
+    pub fn check_chunk_crc(
+        &mut self,
+        chunk_type: [u8; 4],
+        data: &[u8],
+        crc: u32,
+    ) -> Result<(), PngDecodingError> {
+        if !self.checks_crc() {
+            return Ok(());
+        }
+        self.check_crc(chunk_type, chunk_crc(&chunk_type, data), crc)
+    }
+
+    /// A CRC to feed the data of a skipped chunk into, or `None` if CRCs are not
+    /// being checked
+    pub fn chunk_hasher(&self, chunk_type: [u8; 4]) -> Option<Hasher> {
+        if !self.checks_crc() {
+            return None;
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(&chunk_type);
+        Some(hasher)
+    }
+
+    /// Check the CRC of a chunk skipped with the hasher from `chunk_hasher`
+    pub fn check_skipped_crc(
+        &mut self,
+        chunk_type: [u8; 4],
+        hasher: Option<Hasher>,
+        crc: u32,
+    ) -> Result<(), PngDecodingError> {
+        match hasher {
+            Some(hasher) => self.check_crc(chunk_type, hasher.finalize(), crc),
+            None => Ok(()),
+        }
+    }
+
+    /// Handle a chunk read in full by `read_with_options` or `read_async_with_options`,
+    /// collecting its data if it is an IDAT chunk.
+    ///
+    /// Returns whether the chunk was IEND
+    pub fn read_chunk(
+        &mut self,
+        chunk_type: [u8; 4],
+        data: &[u8],
+        crc: u32,
+        idat: &mut Vec<u8>,
+    ) -> Result<bool, PngDecodingError> {
+        match &chunk_type {
+            b"IDAT" => idat.extend_from_slice(data),
+            b"IEND" => {
+                check_iend(data.len() as u32, crc)?;
+                return Ok(true);
+            }
+            _ => self.process_chunk(chunk_type, data)?,
+        }
+        Ok(false)
+    }
+
+    /// Parse any chunk other than IDAT and IEND
+    pub fn process_chunk(
+        &mut self,
+        chunk_type: [u8; 4],
+        data: &[u8],
+    ) -> Result<(), PngDecodingError> {
+        let length = data.len() as u32;
+        let mut buf: &[u8] = data;
+
+        match &chunk_type {
+            // Critical
+            b"IHDR" => {
+                self.ihdr = IHDR::parse(length, &mut buf)?;
+                self.options.limits.check_ihdr(&self.ihdr)?;
+            }
+            b"PLTE" => {
+                match self.ihdr.color_type {
+                    ColorType::Indexed | ColorType::RGB | ColorType::RGBA => {}
+                    ColorType::Grayscale | ColorType::GrayscaleAlpha => {
+                        return Err(ChunkError::UnexpectedPLTEChunk.into())
                     }
-                    ColorType::RGBA | ColorType::GrayscaleAlpha => todo!(),
-                },
-                b"IDAT" => {
-                    let mut v: Vec<u8> = vec![0; length as usize];
-                    f.read_exact(&mut v)?;
-                    idat.extend(v);
                 }
-                b"IEND" => {
-                    let mut iend_crc = [0u8; 4];
-                    f.read_exact(&mut iend_crc)?;
-                    if length != 0 || iend_crc != [174u8, 66, 96, 130] {
-                        return Err(PngDecodingError::InvalidIENDChunk {
-                            found: (length, iend_crc),
-                            expected: IEND,
-                        });
+
+                self.plte = Some(PLTE::parse(length, &mut buf)?);
+            }
+            b"tRNS" => match self.ihdr.color_type {
+                ColorType::Grayscale => {
+                    let mut grayscale_buffer = [0u8; 2];
+                    buf.read_exact(&mut grayscale_buffer)?;
+                    let grayscale = u16::from_be_bytes(grayscale_buffer);
+                    if self.check_sample(chunk_type, &[grayscale]) {
+                        self.ancillary_chunks.tRNS = Some(tRNS::Grayscale { grayscale });
                     }
-                    break;
                 }
+                ColorType::RGB => {
+                    let mut red_buffer = [0u8; 2];
+                    let mut green_buffer = [0u8; 2];
+                    let mut blue_buffer = [0u8; 2];
 
-                // Ancillary
-                b"pHYs" => ancillary_chunks.pHYs = Some(pHYs::parse(length, &mut f)?),
-                b"tEXt" => ancillary_chunks.tEXt.push(tEXt::parse(length, &mut f)?),
-                b"iTXt" => {
-                    let mut keyword_buffer: Vec<u8> = Vec::new();
-                    let mut compressed_buffer = [0u8];
-                    let mut compression_method_buffer = [0u8];
-                    let mut language_tag_buffer = Vec::new();
-                    let mut translated_keyword_buffer = Vec::new();
-
-                    let keyword_len = f.read_until(b'\0', &mut keyword_buffer)?;
-                    f.read_exact(&mut compressed_buffer)?;
-                    f.read_exact(&mut compression_method_buffer)?;
-                    let language_tag_len = f.read_until(0, &mut language_tag_buffer)?;
-                    let translated_keyword_len = f.read_until(0, &mut translated_keyword_buffer)?;
-
-                    let remaining_length = length
-                        - (keyword_len as u32)
-                        - 2
-                        - (language_tag_len as u32)
-                        - (translated_keyword_len as u32);
-
-                    let mut text_buffer: Vec<u8> = vec![0; remaining_length as usize];
-                    f.read_exact(&mut text_buffer)?;
-
-                    // the null byte is included in `read_until()`
-                    keyword_buffer.pop();
-                    language_tag_buffer.pop();
-                    translated_keyword_buffer.pop();
-
-                    let keyword = if let Ok(k) = String::from_utf8(keyword_buffer) {
-                        k
-                    } else {
-                        continue;
-                    };
-                    let compressed = u8::from_be_bytes(compressed_buffer) != 0;
-                    let compression_method = if compressed {
-                        Some(u8::from_be_bytes(compression_method_buffer))
-                    } else {
-                        None
-                    };
-                    let language_tag = if let Ok(lt) = String::from_utf8(language_tag_buffer) {
-                        lt
-                    } else {
-                        continue;
-                    };
-                    let translated_keyword =
-                        if let Ok(tk) = String::from_utf8(translated_keyword_buffer) {
-                            tk
-                        } else {
-                            continue;
-                        };
-                    let text = if compressed {
-                        todo!()
-                    } else if let Ok(t) = String::from_utf8(text_buffer) {
-                        t
+                    buf.read_exact(&mut red_buffer)?;
+                    buf.read_exact(&mut green_buffer)?;
+                    buf.read_exact(&mut blue_buffer)?;
+
+                    let red = u16::from_be_bytes(red_buffer);
+                    let green = u16::from_be_bytes(green_buffer);
+                    let blue = u16::from_be_bytes(blue_buffer);
+
+                    if self.check_sample(chunk_type, &[red, green, blue]) {
+                        self.ancillary_chunks.tRNS = Some(tRNS::RGB { red, green, blue });
+                    }
+                }
+                ColorType::Indexed => {
+                    let mut entries: Vec<u8> = vec![0; length as usize];
+                    buf.read_exact(&mut entries)?;
+                    let palette_len = self.plte.as_ref().map_or(0, |plte| plte.entries.len());
+                    if entries.len() > palette_len {
+                        self.warn_out_of_range(chunk_type, length);
                     } else {
-                        continue;
-                    };
+                        self.ancillary_chunks.tRNS = Some(tRNS::Indexed { entries });
+                    }
+                }
+                // rejected by `check_order` before the chunk is read
+                ColorType::RGBA | ColorType::GrayscaleAlpha => {
+                    return Err(ChunkError::UnexpectedtRNSChunk.into())
+                }
+            },
+            // Ancillary
+            b"pHYs" => match pHYs::parse(length, &mut buf) {
+                Ok(phys) => self.ancillary_chunks.pHYs = Some(phys),
+                Err(PngDecodingError::MetadataError(MetadataError::UnrecognizedUnit { unit })) => {
+                    self.warn_out_of_range(chunk_type, u32::from(unit))
+                }
+                Err(err) => return Err(err),
+            },
+            b"tEXt" => self
+                .ancillary_chunks
+                .tEXt
+                .push(tEXt::parse(length, &mut buf)?),
+            b"iTXt" => {
+                let mut keyword_buffer: Vec<u8> = Vec::new();
+                let mut compressed_buffer = [0u8];
+                let mut compression_method_buffer = [0u8];
+                let mut language_tag_buffer = Vec::new();
+                let mut translated_keyword_buffer = Vec::new();
+
+                buf.read_until(b'\0', &mut keyword_buffer)?;
+                buf.read_exact(&mut compressed_buffer)?;
+                buf.read_exact(&mut compression_method_buffer)?;
+                buf.read_until(0, &mut language_tag_buffer)?;
+                buf.read_until(0, &mut translated_keyword_buffer)?;
+
+                let mut text_buffer: Vec<u8> = Vec::new();
+                buf.read_to_end(&mut text_buffer)?;
 
-                    let itxt = iTXt {
-                        keyword,
-                        compressed,
-                        compression_method,
-                        language_tag,
-                        translated_keyword,
-                        text,
+                // the null byte is included in `read_until()`
+                keyword_buffer.pop();
+                language_tag_buffer.pop();
+                translated_keyword_buffer.pop();
+
+                // the keyword is Latin-1, like that of tEXt
+                let keyword = decode_latin1(&keyword_buffer);
+                let compressed = u8::from_be_bytes(compressed_buffer) != 0;
+                let compression_method = if compressed {
+                    Some(u8::from_be_bytes(compression_method_buffer))
+                } else {
+                    None
+                };
+
+                if compressed {
+                    text_buffer = match self.inflate_text(&text_buffer)? {
+                        Some(text) if compression_method == Some(0) => text,
+                        _ => {
+                            self.warnings.push(DecodeWarning::InvalidCompressedText {
+                                chunk_type,
+                                keyword,
+                            });
+                            return Ok(());
+                        }
                     };
-                    ancillary_chunks.itxt.push(itxt);
                 }
-                b"bKGD" => match ihdr.color_type {
-                    ColorType::Grayscale | ColorType::GrayscaleAlpha => {
-                        let mut grayscale_buffer = [0u8; 2];
-                        f.read_exact(&mut grayscale_buffer)?;
-                        let grayscale = u16::from_be_bytes(grayscale_buffer);
-                        ancillary_chunks.bKGD = Some(bKGD::Grayscale { grayscale });
+
+                let mut valid = true;
+                let mut decode_utf8 = |bytes: Vec<u8>| {
+                    String::from_utf8(bytes).unwrap_or_else(|err| {
+                        valid = false;
+                        String::from_utf8_lossy(err.as_bytes()).into_owned()
+                    })
+                };
+                let language_tag = decode_utf8(language_tag_buffer);
+                let translated_keyword = decode_utf8(translated_keyword_buffer);
+                let text = decode_utf8(text_buffer);
+
+                if !valid {
+                    self.warnings.push(DecodeWarning::InvalidText {
+                        chunk_type,
+                        keyword: keyword.clone(),
+                    });
+                }
+
+                let itxt = iTXt {
+                    keyword,
+                    compressed,
+                    compression_method,
+                    language_tag,
+                    translated_keyword,
+                    text,
+                };
+                self.ancillary_chunks.itxt.push(itxt);
+            }
+            b"bKGD" => match self.ihdr.color_type {
+                ColorType::Grayscale | ColorType::GrayscaleAlpha => {
+                    let mut grayscale_buffer = [0u8; 2];
+                    buf.read_exact(&mut grayscale_buffer)?;
+                    let grayscale = u16::from_be_bytes(grayscale_buffer);
+                    if self.check_sample(chunk_type, &[grayscale]) {
+                        self.ancillary_chunks.bKGD = Some(bKGD::Grayscale { grayscale });
                     }
-                    ColorType::RGB | ColorType::RGBA => {
-                        let mut red_buffer = [0u8; 2];
-                        let mut green_buffer = [0u8; 2];
-                        let mut blue_buffer = [0u8; 2];
-                        f.read_exact(&mut red_buffer)?;
-                        f.read_exact(&mut green_buffer)?;
-                        f.read_exact(&mut blue_buffer)?;
-                        let red = u16::from_be_bytes(red_buffer);
-                        let green = u16::from_be_bytes(green_buffer);
-                        let blue = u16::from_be_bytes(blue_buffer);
-                        ancillary_chunks.bKGD = Some(bKGD::RGB { red, green, blue });
+                }
+                ColorType::RGB | ColorType::RGBA => {
+                    let mut red_buffer = [0u8; 2];
+                    let mut green_buffer = [0u8; 2];
+                    let mut blue_buffer = [0u8; 2];
+                    buf.read_exact(&mut red_buffer)?;
+                    buf.read_exact(&mut green_buffer)?;
+                    buf.read_exact(&mut blue_buffer)?;
+                    let red = u16::from_be_bytes(red_buffer);
+                    let green = u16::from_be_bytes(green_buffer);
+                    let blue = u16::from_be_bytes(blue_buffer);
+                    if self.check_sample(chunk_type, &[red, green, blue]) {
+                        self.ancillary_chunks.bKGD = Some(bKGD::RGB { red, green, blue });
+                    }
+                }
+                ColorType::Indexed => {
+                    let mut palette_index_buffer = [0u8];
+                    buf.read_exact(&mut palette_index_buffer)?;
+                    let palette_index = u8::from_be_bytes(palette_index_buffer);
+                    let plte = self.plte.as_ref().ok_or(ChunkError::PLTEChunkNotFound)?;
+                    match plte.entries.get(usize::from(palette_index)) {
+                        Some(&rgb) => {
+                            self.ancillary_chunks.bKGD = Some(bKGD::Palette { palette_index, rgb })
+                        }
+                        None => self.warn_out_of_range(chunk_type, u32::from(palette_index)),
+                    }
+                }
+            },
+            b"gAMA" => {
+                if length != 4 {
+                    return Err(ChunkError::InvalidgAMALength.into());
+                }
+                let mut gamma_buffer = [0u8; 4];
+                buf.read_exact(&mut gamma_buffer)?;
+                let gamma = u32::from_be_bytes(gamma_buffer);
+                if gamma == 0 {
+                    self.warn_out_of_range(chunk_type, gamma);
+                } else {
+                    self.ancillary_chunks.gama = Some(gAMA { gamma });
+                }
+            }
+            b"cHRM" => self.ancillary_chunks.chrm = Some(cHRM::parse(length, &mut buf)?),
+            b"iCCP" => self.ancillary_chunks.iCCP = Some(iCCP::parse(length, &mut buf)?),
+            b"sBIT" => {
+                let sbit = match self.ihdr.color_type {
+                    ColorType::Grayscale => {
+                        let mut grayscale_buffer = [0];
+                        buf.read_exact(&mut grayscale_buffer)?;
+                        let grayscale = u8::from_be_bytes(grayscale_buffer);
+                        Some(sBIT::Grayscale { grayscale })
+                    }
+                    ColorType::RGB => {
+                        let mut red_buffer = [0u8];
+                        let mut green_buffer = [0u8];
+                        let mut blue_buffer = [0u8];
+
+                        buf.read_exact(&mut red_buffer)?;
+                        buf.read_exact(&mut green_buffer)?;
+                        buf.read_exact(&mut blue_buffer)?;
+
+                        let red = u8::from_be_bytes(red_buffer);
+                        let green = u8::from_be_bytes(green_buffer);
+                        let blue = u8::from_be_bytes(blue_buffer);
+
+                        Some(sBIT::RGB { red, green, blue })
                     }
                     ColorType::Indexed => {
-                        let mut palette_index_buffer = [0u8];
-                        f.read_exact(&mut palette_index_buffer)?;
-                        let palette_index = u8::from_be_bytes(palette_index_buffer);
-                        let rgb = if let Some(p) = plte.clone() {
-                            p
-                        } else {
-                            unreachable!()
-                        };
-                        ancillary_chunks.bKGD = Some(bKGD::Palette {
-                            palette_index,
-                            rgb: rgb[palette_index],
-                        });
+                        let mut red_buffer = [0u8];
+                        let mut green_buffer = [0u8];
+                        let mut blue_buffer = [0u8];
+
+                        buf.read_exact(&mut red_buffer)?;
+                        buf.read_exact(&mut green_buffer)?;
+                        buf.read_exact(&mut blue_buffer)?;
+
+                        let red = u8::from_be_bytes(red_buffer);
+                        let green = u8::from_be_bytes(green_buffer);
+                        let blue = u8::from_be_bytes(blue_buffer);
+
+                        Some(sBIT::Indexed { red, green, blue })
                     }
-                },
-                b"gAMA" => {
-                    if length != 4 {
-                        return Err(ChunkError::InvalidgAMALength.into());
+                    ColorType::GrayscaleAlpha => {
+                        let mut grayscale_buffer = [0u8];
+                        let mut alpha_buffer = [0u8];
+
+                        buf.read_exact(&mut grayscale_buffer)?;
+                        buf.read_exact(&mut alpha_buffer)?;
+
+                        let grayscale = u8::from_be_bytes(grayscale_buffer);
+                        let alpha = u8::from_be_bytes(alpha_buffer);
+
+                        Some(sBIT::GrayscaleAlpha { grayscale, alpha })
                     }
-                    let mut gamma_buffer = [0u8; 4];
-                    f.read_exact(&mut gamma_buffer)?;
-                    let gamma = u32::from_be_bytes(gamma_buffer);
-                    ancillary_chunks.gama = Some(gAMA { gamma });
+                    ColorType::RGBA => {
+                        let mut red_buffer = [0u8];
+                        let mut green_buffer = [0u8];
+                        let mut blue_buffer = [0u8];
+                        let mut alpha_buffer = [0u8];
+
+                        buf.read_exact(&mut red_buffer)?;
+                        buf.read_exact(&mut green_buffer)?;
+                        buf.read_exact(&mut blue_buffer)?;
+                        buf.read_exact(&mut alpha_buffer)?;
+
+                        let red = u8::from_be_bytes(red_buffer);
+                        let green = u8::from_be_bytes(green_buffer);
+                        let blue = u8::from_be_bytes(blue_buffer);
+                        let alpha = u8::from_be_bytes(alpha_buffer);
+
+                        Some(sBIT::RGBA {
+                            red,
+                            green,
+                            blue,
+                            alpha,
+                        })
+                    }
+                };
+
+                // indexed images have 8 bit palette entries, whatever their bit depth
+                let sample_depth = match self.ihdr.color_type {
+                    ColorType::Indexed => 8,
+                    _ => self.ihdr.bit_depth,
+                };
+                match data.iter().find(|&&bits| bits == 0 || bits > sample_depth) {
+                    Some(&bits) => self.warn_out_of_range(chunk_type, u32::from(bits)),
+                    None => self.ancillary_chunks.sBIT = sbit,
                 }
-                b"cHRM" => ancillary_chunks.chrm = Some(cHRM::parse(length, &mut f)?),
-                b"iCCP" => ancillary_chunks.iCCP = Some(iCCP::parse(length, &mut f)?),
-                b"sBIT" => {
-                    ancillary_chunks.sBIT = match ihdr.color_type {
-                        ColorType::Grayscale => {
-                            let mut grayscale_buffer = [0];
-                            f.read_exact(&mut grayscale_buffer)?;
-                            let grayscale = u8::from_be_bytes(grayscale_buffer);
-                            Some(sBIT::Grayscale { grayscale })
-                        }
-                        ColorType::RGB => {
-                            let mut red_buffer = [0u8];
-                            let mut green_buffer = [0u8];
-                            let mut blue_buffer = [0u8];
+            }
+            b"sRGB" => {
+                let mut intent_buffer = [0];
+                buf.read_exact(&mut intent_buffer)?;
 
-                            f.read_exact(&mut red_buffer)?;
-                            f.read_exact(&mut green_buffer)?;
-                            f.read_exact(&mut blue_buffer)?;
+                match sRGB::from_u8(u8::from_be_bytes(intent_buffer)) {
+                    Ok(intent) => self.ancillary_chunks.sRGB = Some(intent),
+                    Err(_) => self.warn_out_of_range(chunk_type, u32::from(intent_buffer[0])),
+                }
+            }
+            _ => {
+                if let Some(handler) = self.options.handler_for(chunk_type) {
+                    return handler.parse(data, &mut self.custom_chunks);
+                }
 
-                            let red = u8::from_be_bytes(red_buffer);
-                            let green = u8::from_be_bytes(green_buffer);
-                            let blue = u8::from_be_bytes(blue_buffer);
+                let is_critical = !get_bit_at(chunk_type[0], 5);
+                let is_public = !get_bit_at(chunk_type[1], 5);
+                let is_safe_to_copy = get_bit_at(chunk_type[2], 5);
+                if is_critical {
+                    return Err(ChunkError::UnrecognizedCriticalChunk(chunk_type).into());
+                }
+                // a chunk kept by its own policy is expected, so is not worth a warning
+                if !self.options.has_policy(chunk_type) {
+                    self.warnings
+                        .push(DecodeWarning::UnrecognizedChunk(chunk_type));
+                }
+                self.unrecognized_chunks.push(UnrecognizedChunk {
+                    length,
+                    chunk_type,
+                    bytes: data.to_vec(),
+                    is_critical,
+                    is_public,
+                    is_safe_to_copy,
+                })
+            }
+        }
 
-                            Some(sBIT::RGB { red, green, blue })
-                        }
-                        ColorType::Indexed => {
-                            let mut red_buffer = [0u8];
-                            let mut green_buffer = [0u8];
-                            let mut blue_buffer = [0u8];
+        Ok(())
+    }
 
-                            f.read_exact(&mut red_buffer)?;
-                            f.read_exact(&mut green_buffer)?;
-                            f.read_exact(&mut blue_buffer)?;
+    fn warn_out_of_range(&mut self, chunk_type: [u8; 4], value: u32) {
+        self.warnings
+            .push(DecodeWarning::ValueOutOfRange { chunk_type, value });
+    }
 
-                            let red = u8::from_be_bytes(red_buffer);
-                            let green = u8::from_be_bytes(green_buffer);
-                            let blue = u8::from_be_bytes(blue_buffer);
+    /// Check that the samples of a bKGD or tRNS chunk fit in the image's bit depth,
+    /// recording a warning if they do not
+    fn check_sample(&mut self, chunk_type: [u8; 4], samples: &[u16]) -> bool {
+        let max = (1u32 << self.ihdr.bit_depth) - 1;
+        match samples.iter().find(|&&sample| u32::from(sample) > max) {
+            Some(&sample) => {
+                self.warn_out_of_range(chunk_type, u32::from(sample));
+                false
+            }
+            None => true,
+        }
+    }
 
-                            Some(sBIT::Indexed { red, green, blue })
-                        }
-                        ColorType::GrayscaleAlpha => {
-                            let mut grayscale_buffer = [0u8];
-                            let mut alpha_buffer = [0u8];
+    /// Inflate the text of a compressed iTXt chunk, counting it towards the metadata
+    /// limit. Returns `None` if the data is not a valid zlib stream
+    fn inflate_text(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>, LimitError> {
+        let max = self.options.limits.max_metadata_bytes;
+        let budget = max.saturating_sub(self.metadata_bytes);
 
-                            f.read_exact(&mut grayscale_buffer)?;
-                            f.read_exact(&mut alpha_buffer)?;
+        let mut text = Vec::new();
+        if ZlibDecoder::new(data)
+            .take(budget.saturating_add(1))
+            .read_to_end(&mut text)
+            .is_err()
+        {
+            return Ok(None);
+        }
 
-                            let grayscale = u8::from_be_bytes(grayscale_buffer);
-                            let alpha = u8::from_be_bytes(alpha_buffer);
+        self.metadata_bytes += text.len() as u64;
+        if self.metadata_bytes > max {
+            return Err(LimitError::TooMuchMetadata {
+                total: self.metadata_bytes,
+                max,
+            });
+        }
 
-                            Some(sBIT::GrayscaleAlpha { grayscale, alpha })
-                        }
-                        ColorType::RGBA => {
-                            let mut red_buffer = [0u8];
-                            let mut green_buffer = [0u8];
-                            let mut blue_buffer = [0u8];
-                            let mut alpha_buffer = [0u8];
-
-                            f.read_exact(&mut red_buffer)?;
-                            f.read_exact(&mut green_buffer)?;
-                            f.read_exact(&mut blue_buffer)?;
-                            f.read_exact(&mut alpha_buffer)?;
-
-                            let red = u8::from_be_bytes(red_buffer);
-                            let green = u8::from_be_bytes(green_buffer);
-                            let blue = u8::from_be_bytes(blue_buffer);
-                            let alpha = u8::from_be_bytes(alpha_buffer);
-
-                            Some(sBIT::RGBA {
-                                red,
-                                green,
-                                blue,
-                                alpha,
-                            })
-                        }
-                    }
+        Ok(Some(text))
+    }
+
+    pub fn into_info(self) -> PngInfo {
+        PngInfo {
+            ihdr: self.ihdr,
+            plte: self.plte,
+            ancillary_chunks: self.ancillary_chunks,
+            unrecognized_chunks: self.unrecognized_chunks,
+            custom_chunks: self.custom_chunks,
+            warnings: self.warnings,
+        }
+    }
+
+    pub fn into_png(self, idat: Vec<u8>) -> Png {
+        Png {
+            ihdr: self.ihdr,
+            idat,
+            decoded_buffer: None,
+            unrecognized_chunks: self.unrecognized_chunks,
+            ancillary_chunks: self.ancillary_chunks,
+            custom_chunks: self.custom_chunks,
+            plte: self.plte,
+            warnings: self.warnings,
+        }
+    }
+}
+
+/// How far into a file `PngDecoder::probe` reads
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
+pub enum ProbeStop {
+    /// Stop as soon as the IHDR chunk has been read
+    #[default]
+    AfterIHDR,
+    /// Read every chunk that comes before the image data
+    BeforeIDAT,
+}
+
+/// The metadata of a file, read without any of its image data
+#[derive(Debug, Clone)]
+pub struct PngInfo {
+    pub ihdr: IHDR,
+    pub plte: Option<PLTE>,
+    pub ancillary_chunks: AncillaryChunks,
+    pub unrecognized_chunks: Vec<UnrecognizedChunk>,
+    pub custom_chunks: CustomChunks,
+    pub warnings: Vec<DecodeWarning>,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct PngDecoder;
+
+impl PngDecoder {
+    pub fn read<T: BufRead + Read>(f: T) -> Result<Png, PngDecodingError> {
+        PngDecoder::read_with_options(f, &DecoderOptions::default())
+    }
+
+    pub fn read_with_options<T: BufRead + Read>(
+        mut f: T,
+        options: &DecoderOptions,
+    ) -> Result<Png, PngDecodingError> {
+        let mut state = DecoderState::new(options);
+        let mut idat: Vec<u8> = Vec::new();
+
+        state.locate_errors(|state| {
+            read_header(&mut f)?;
+
+            loop {
+                state.start_chunk();
+                let (length, chunk_type) = read_chunk_header(&mut f)?;
+                state.check_length(length, chunk_type)?;
+                let accepted = state.check_order(chunk_type)?;
+                if !accepted {
+                    skip_chunk_data(&mut f, state, length, chunk_type)?;
+                    continue;
                 }
-                b"sRGB" => {
-                    let mut intent_buffer = [0];
-                    f.read_exact(&mut intent_buffer)?;
 
-                    ancillary_chunks.sRGB = Some(sRGB::from_u8(u8::from_be_bytes(intent_buffer))?);
+                let (data, crc) = read_chunk_data(&mut f, state, length, chunk_type)?;
+                if state.read_chunk(chunk_type, &data, crc, &mut idat)? {
+                    break;
                 }
-                _ => {
-                    let is_critical = !get_bit_at(chunk_type[0], 5);
-                    let is_public = !get_bit_at(chunk_type[1], 5);
-                    let is_safe_to_copy = get_bit_at(chunk_type[2], 5);
-                    if is_critical {
-                        return Err(ChunkError::UnrecognizedCriticalChunk(chunk_type).into());
+            }
+
+            Ok(())
+        })?;
+
+        Ok(state.into_png(idat))
+    }
+
+    pub fn probe<T: Read>(f: T, stop: ProbeStop) -> Result<PngInfo, PngDecodingError> {
+        PngDecoder::probe_with_options(f, stop, &DecoderOptions::default())
+    }
+
+    /// Read the IHDR chunk, and optionally the rest of the metadata before the image
+    /// data, without reading any further into the file.
+    ///
+    /// Chunks that come after the image data are not seen
+    pub fn probe_with_options<T: Read>(
+        mut f: T,
+        stop: ProbeStop,
+        options: &DecoderOptions,
+    ) -> Result<PngInfo, PngDecodingError> {
+        let mut state = DecoderState::new(options);
+
+        state.locate_errors(|state| {
+            read_header(&mut f)?;
+
+            state.start_chunk();
+            let (length, chunk_type) = read_chunk_header(&mut f)?;
+            state.check_length(length, chunk_type)?;
+            if &chunk_type != b"IHDR" {
+                return Err(ChunkError::IHDRChunkNotFound.into());
+            }
+            state.check_order(chunk_type)?;
+            let (data, _) = read_chunk_data(&mut f, state, length, chunk_type)?;
+            state.process_chunk(chunk_type, &data)?;
+
+            if stop == ProbeStop::BeforeIDAT {
+                loop {
+                    state.start_chunk();
+                    let (length, chunk_type) = read_chunk_header(&mut f)?;
+                    if matches!(&chunk_type, b"IDAT" | b"IEND") {
+                        break;
+                    }
+
+                    state.check_length(length, chunk_type)?;
+                    if state.check_order(chunk_type)? {
+                        let (data, _) = read_chunk_data(&mut f, state, length, chunk_type)?;
+                        state.process_chunk(chunk_type, &data)?;
+                    } else {
+                        skip_chunk_data(&mut f, state, length, chunk_type)?;
                     }
-                    let mut buffer: Vec<u8> = vec![0; length as usize];
-                    f.read_exact(&mut buffer)?;
-                    unrecognized_chunks.push(UnrecognizedChunk {
-                        length,
-                        chunk_type,
-                        bytes: buffer,
-                        is_critical,
-                        is_public,
-                        is_safe_to_copy,
-                    })
                 }
             }
 
-            let mut crc = [0; 4];
-            f.read_exact(&mut crc)?;
+            Ok(())
+        })?;
+
+        Ok(state.into_info())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    /// A valid grayscale PNG with an ancillary chunk whose CRC has been corrupted
+    fn corrupt_crc() -> Vec<u8> {
+        let mut gama = test_utils::chunk(b"gAMA", &45455u32.to_be_bytes());
+        let last = gama.len() - 1;
+        gama[last] ^= 0xff;
+        test_utils::png(1, 1, 8, ColorType::Grayscale, &[gama], &[0])
+    }
+
+    #[test]
+    fn crc_mismatch_is_an_error_by_default() {
+        match PngDecoder::read(corrupt_crc().as_slice()).map_err(PngDecodingError::into_kind) {
+            Err(PngDecodingError::ChunkError(ChunkError::CrcMismatch {
+                chunk_type,
+                expected,
+                found,
+            })) => {
+                assert_eq!(&chunk_type, b"gAMA");
+                assert_eq!(expected ^ 0xff, found);
+            }
+            other => panic!("expected CRC mismatch, found {:?}", other),
         }
+    }
+
+    #[test]
+    fn errors_report_where_they_happened() {
+        use std::error::Error;
+
+        let err = PngDecoder::read(corrupt_crc().as_slice()).unwrap_err();
+        assert_eq!(
+            err.location(),
+            Some(&ErrorLocation {
+                offset: 33,
+                chunk_index: Some(1),
+                chunk_type: Some(*b"gAMA"),
+            })
+        );
+        assert_eq!(
+            err.to_string(),
+            "failed to decode 'gAMA' chunk 1 at byte 33"
+        );
+        assert!(matches!(
+            err.source()
+                .and_then(|source| source.downcast_ref::<PngDecodingError>()),
+            Some(PngDecodingError::ChunkError(ChunkError::CrcMismatch { .. }))
+        ));
+
+        // the header of the chunk after IHDR is cut short
+        let file = corrupt_crc();
+        let err = PngDecoder::read(&file[..35]).unwrap_err();
+        assert_eq!(
+            err.location(),
+            Some(&ErrorLocation {
+                offset: 33,
+                chunk_index: Some(1),
+                chunk_type: None,
+            })
+        );
+        assert!(matches!(err.kind(), PngDecodingError::IoError(_)));
+    }
+
+    #[test]
+    fn crc_mismatch_can_warn_or_be_skipped() {
+        let options = DecoderOptions::new().crc_check(CrcCheck::Warn);
+        let png = PngDecoder::read_with_options(corrupt_crc().as_slice(), &options).unwrap();
+        assert!(matches!(
+            png.warnings.as_slice(),
+            [DecodeWarning::ChunkError(ChunkError::CrcMismatch { .. })]
+        ));
+        assert_eq!(png.ancillary_chunks.gama, Some(gAMA { gamma: 45455 }));
+
+        let options = DecoderOptions::new().crc_check(CrcCheck::Skip);
+        let png = PngDecoder::read_with_options(corrupt_crc().as_slice(), &options).unwrap();
+        assert!(png.warnings.is_empty());
+    }
+
+    fn read_with_limits(file: &[u8], limits: DecoderLimits) -> Result<Png, PngDecodingError> {
+        PngDecoder::read_with_options(file, &DecoderOptions::new().limits(limits))
+            .map_err(PngDecodingError::into_kind)
+    }
 
-        idat = match ihdr.interlace_method {
-            // adam7
-            1 => interlacing::decode_adam7(&idat),
-            // no interlacing or invalid value
-            _ => idat,
+    #[test]
+    fn exceeded_limits_are_errors() {
+        let text = test_utils::chunk(b"tEXt", b"Title\0A small image");
+        let file = test_utils::png(4, 2, 8, ColorType::RGB, &[text], &[0; 24]);
+        let unlimited = DecoderLimits::unlimited();
+
+        assert!(read_with_limits(&file, unlimited).is_ok());
+
+        let check = |limits: DecoderLimits| match read_with_limits(&file, limits) {
+            Err(PngDecodingError::LimitError(err)) => err,
+            other => panic!("expected limit error, found {:?}", other),
         };
 
-        Ok(Png {
-            ihdr,
-            idat,
-            decoded_buffer: None,
-            unrecognized_chunks,
-            ancillary_chunks,
-            plte,
-        })
+        assert_eq!(
+            check(DecoderLimits {
+                max_width: 3,
+                ..unlimited
+            }),
+            LimitError::WidthTooLarge { width: 4, max: 3 }
+        );
+        assert_eq!(
+            check(DecoderLimits {
+                max_height: 1,
+                ..unlimited
+            }),
+            LimitError::HeightTooLarge { height: 2, max: 1 }
+        );
+        assert_eq!(
+            check(DecoderLimits {
+                max_pixels: 7,
+                ..unlimited
+            }),
+            LimitError::TooManyPixels { pixels: 8, max: 7 }
+        );
+        assert_eq!(
+            check(DecoderLimits {
+                max_decompressed_bytes: 25,
+                ..unlimited
+            }),
+            LimitError::DecompressedDataTooLarge {
+                length: 26,
+                max: 25
+            }
+        );
+        assert_eq!(
+            check(DecoderLimits {
+                max_metadata_bytes: 10,
+                ..unlimited
+            }),
+            LimitError::TooMuchMetadata { total: 19, max: 10 }
+        );
+        assert_eq!(
+            check(DecoderLimits {
+                max_chunk_size: 12,
+                ..unlimited
+            }),
+            LimitError::ChunkTooLarge {
+                chunk_type: *b"IHDR",
+                length: 13,
+                max: 12
+            }
+        );
+    }
+
+    #[test]
+    fn claimed_chunk_length_is_not_preallocated() {
+        let mut file = test_utils::png(1, 1, 8, ColorType::Grayscale, &[], &[0]);
+        // claim a 1 GiB tEXt chunk, then end the file
+        file.truncate(33);
+        file.extend_from_slice(&(1u32 << 30).to_be_bytes());
+        file.extend_from_slice(b"tEXt");
+
+        let limits = DecoderLimits::unlimited();
+        assert!(matches!(
+            read_with_limits(&file, limits),
+            Err(PngDecodingError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn probe_stops_before_image_data() {
+        let file = test_utils::png(
+            3,
+            2,
+            8,
+            ColorType::Grayscale,
+            &[test_utils::chunk(b"tEXt", b"Title\0probe")],
+            &[0; 6],
+        );
+        // everything from the IDAT chunk onwards is missing
+        let header_len = 8 + 25 + 12 + "Title\0probe".len();
+        let file = &file[..header_len];
+
+        let info = PngDecoder::probe(&file[..33], ProbeStop::AfterIHDR).unwrap();
+        assert_eq!((info.ihdr.width, info.ihdr.height), (3, 2));
+        assert!(info.ancillary_chunks.tEXt.is_empty());
+
+        assert!(PngDecoder::probe(file, ProbeStop::BeforeIDAT).is_err());
+        let mut file = file.to_vec();
+        file.extend_from_slice(&[0, 0, 0, 5]);
+        file.extend_from_slice(b"IDAT");
+        let info = PngDecoder::probe(file.as_slice(), ProbeStop::BeforeIDAT).unwrap();
+        assert_eq!(info.ancillary_chunks.tEXt[0].text, "probe");
+    }
+
+    #[test]
+    fn probe_requires_ihdr_first() {
+        let mut file = HEADER.to_vec();
+        file.extend(test_utils::chunk(b"gAMA", &45455u32.to_be_bytes()));
+        assert!(matches!(
+            PngDecoder::probe(file.as_slice(), ProbeStop::AfterIHDR)
+                .map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::ChunkError(ChunkError::IHDRChunkNotFound))
+        ));
+    }
+
+    /// A file with the given chunks between IHDR and IEND
+    fn with_chunks(color_type: ColorType, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = HEADER.to_vec();
+        file.extend(test_utils::ihdr(1, 1, 8, color_type));
+        for chunk in chunks {
+            file.extend_from_slice(chunk);
+        }
+        file.extend_from_slice(&IEND);
+        file
+    }
+
+    #[test]
+    fn chunk_order_violations() {
+        let gama = |gamma: u32| test_utils::chunk(b"gAMA", &gamma.to_be_bytes());
+        let plte = test_utils::chunk(b"PLTE", &[1, 2, 3]);
+        let bkgd = test_utils::chunk(b"bKGD", &[0]);
+        let idat = test_utils::chunk(b"IDAT", &test_utils::zlib(&[0, 0]));
+        let text = test_utils::chunk(b"tEXt", b"Title\0order");
+
+        let cases = [
+            (
+                with_chunks(ColorType::Grayscale, &[gama(1), gama(2), idat.clone()]),
+                ChunkError::DuplicateChunk(*b"gAMA"),
+            ),
+            (
+                with_chunks(ColorType::Indexed, &[bkgd, plte.clone(), idat.clone()]),
+                ChunkError::ChunkBeforePLTE(*b"bKGD"),
+            ),
+            (
+                with_chunks(ColorType::RGB, &[plte.clone(), gama(1), idat.clone()]),
+                ChunkError::ChunkAfterPLTE(*b"gAMA"),
+            ),
+            (
+                with_chunks(ColorType::RGB, &[idat.clone(), plte]),
+                ChunkError::ChunkAfterIDAT(*b"PLTE"),
+            ),
+            (
+                with_chunks(ColorType::Grayscale, &[idat.clone(), text, idat.clone()]),
+                ChunkError::NonConsecutiveIDAT,
+            ),
+            (
+                with_chunks(
+                    ColorType::RGBA,
+                    &[
+                        test_utils::chunk(b"tRNS", &[0, 1, 0, 2, 0, 3]),
+                        idat.clone(),
+                    ],
+                ),
+                ChunkError::UnexpectedtRNSChunk,
+            ),
+            (
+                with_chunks(ColorType::Indexed, &[idat]),
+                ChunkError::PLTEChunkNotFound,
+            ),
+        ];
+
+        let lenient = DecoderOptions::new().chunk_order(ChunkOrder::Lenient);
+
+        for (file, expected) in cases.iter() {
+            match PngDecoder::read(file.as_slice()).map_err(PngDecodingError::into_kind) {
+                Err(PngDecodingError::ChunkError(err)) => assert_eq!(&err, expected),
+                other => panic!("expected {:?}, found {:?}", expected, other),
+            }
+
+            let png = PngDecoder::read_with_options(file.as_slice(), &lenient).unwrap();
+            assert_eq!(
+                png.warnings,
+                vec![DecodeWarning::ChunkError(expected.clone())]
+            );
+        }
+
+        // out of place chunks are ignored, so the first gAMA is kept
+        let png = PngDecoder::read_with_options(cases[0].0.as_slice(), &lenient).unwrap();
+        assert_eq!(png.ancillary_chunks.gama, Some(gAMA { gamma: 1 }));
+        let png = PngDecoder::read_with_options(cases[1].0.as_slice(), &lenient).unwrap();
+        assert!(png.ancillary_chunks.bKGD.is_none());
+
+        // an IDAT kept despite a violation still counts towards later checks
+        let idat = test_utils::chunk(b"IDAT", &test_utils::zlib(&[0, 0]));
+        let text = test_utils::chunk(b"tEXt", b"Title\0order");
+        let file = with_chunks(
+            ColorType::Indexed,
+            &[idat.clone(), idat.clone(), text, idat],
+        );
+        let png = PngDecoder::read_with_options(file.as_slice(), &lenient).unwrap();
+        assert_eq!(
+            png.warnings,
+            vec![
+                DecodeWarning::ChunkError(ChunkError::PLTEChunkNotFound),
+                DecodeWarning::ChunkError(ChunkError::NonConsecutiveIDAT),
+            ]
+        );
+    }
+
+    #[test]
+    fn iend_requires_ihdr_and_idat() {
+        let mut signature_only = HEADER.to_vec();
+        signature_only.extend_from_slice(&IEND);
+        let no_image_data = with_chunks(ColorType::Grayscale, &[]);
+
+        for (file, expected) in [
+            (signature_only, ChunkError::IHDRChunkNotFound),
+            (no_image_data, ChunkError::IDATChunkNotFound),
+        ]
+        .iter()
+        {
+            match PngDecoder::read(file.as_slice()).map_err(PngDecodingError::into_kind) {
+                Err(PngDecodingError::ChunkError(err)) => assert_eq!(&err, expected),
+                other => panic!("expected {:?}, found {:?}", expected, other),
+            }
+        }
+    }
+
+    #[test]
+    fn questionable_chunks_are_warnings() {
+        let mut itxt = b"Title\0\0\0en\0\0".to_vec();
+        itxt.extend_from_slice(&[b'a', 0xff]);
+        let mut ztxt = b"Comment\0\x01\0\0\0".to_vec();
+        ztxt.extend(test_utils::zlib("compressed ✓".as_bytes()));
+
+        let file = with_chunks(
+            ColorType::Grayscale,
+            &[
+                test_utils::chunk(b"gAMA", &0u32.to_be_bytes()),
+                test_utils::chunk(b"bKGD", &[1, 0]),
+                test_utils::chunk(b"tEXt", b"Author\0Ren\xe9"),
+                test_utils::chunk(b"iTXt", &itxt),
+                test_utils::chunk(b"iTXt", &ztxt),
+                test_utils::chunk(b"prIv", &[1, 2, 3]),
+                test_utils::chunk(b"IDAT", &test_utils::zlib(&[0, 0])),
+            ],
+        );
+
+        let png = PngDecoder::read(file.as_slice()).unwrap();
+        assert_eq!(
+            png.warnings,
+            vec![
+                DecodeWarning::ValueOutOfRange {
+                    chunk_type: *b"gAMA",
+                    value: 0
+                },
+                DecodeWarning::ValueOutOfRange {
+                    chunk_type: *b"bKGD",
+                    value: 256
+                },
+                DecodeWarning::InvalidText {
+                    chunk_type: *b"iTXt",
+                    keyword: "Title".to_owned()
+                },
+                DecodeWarning::UnrecognizedChunk(*b"prIv"),
+            ]
+        );
+
+        assert!(png.ancillary_chunks.gama.is_none());
+        assert!(png.ancillary_chunks.bKGD.is_none());
+        assert_eq!(png.ancillary_chunks.tEXt[0].text, "René");
+        assert_eq!(png.ancillary_chunks.itxt[0].text, "a\u{fffd}");
+        assert_eq!(png.ancillary_chunks.itxt[1].text, "compressed ✓");
+        assert_eq!(png.unrecognized_chunks.len(), 1);
+    }
+
+    #[test]
+    fn chunk_policies() {
+        let file = with_chunks(
+            ColorType::Grayscale,
+            &[
+                test_utils::chunk(b"gAMA", &45455u32.to_be_bytes()),
+                test_utils::chunk(b"tEXt", &[b'a'; 100]),
+                test_utils::chunk(b"prIv", b"kept"),
+                test_utils::chunk(b"ofFs", b"ignored"),
+                test_utils::chunk(b"IDAT", &test_utils::zlib(&[0, 0])),
+            ],
+        );
+
+        let limits = DecoderLimits {
+            max_metadata_bytes: 20,
+            ..DecoderLimits::unlimited()
+        };
+        let options = DecoderOptions::new()
+            .limits(limits)
+            .chunk_policy(*b"tEXt", ChunkPolicy::Ignore)
+            .chunk_policy(*b"prIv", ChunkPolicy::Keep)
+            .unknown_chunks(ChunkPolicy::Ignore);
+
+        // ignored chunks do not count towards the metadata limit
+        let png = PngDecoder::read_with_options(file.as_slice(), &options).unwrap();
+        assert_eq!(png.ancillary_chunks.gama, Some(gAMA { gamma: 45455 }));
+        assert!(png.ancillary_chunks.tEXt.is_empty());
+        assert_eq!(png.unrecognized_chunks.len(), 1);
+        assert_eq!(&png.unrecognized_chunks[0].chunk_type, b"prIv");
+        assert!(png.warnings.is_empty());
+
+        let options = options.chunk_policy(*b"gAMA", ChunkPolicy::Reject);
+        assert!(matches!(
+            PngDecoder::read_with_options(file.as_slice(), &options)
+                .map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::ChunkError(ChunkError::RejectedChunk(chunk_type))) if &chunk_type == b"gAMA"
+        ));
+    }
+
+    #[test]
+    fn ihdr_must_come_first() {
+        let mut file = HEADER.to_vec();
+        file.extend(test_utils::chunk(b"gAMA", &45455u32.to_be_bytes()));
+        file.extend(test_utils::ihdr(1, 1, 8, ColorType::Grayscale));
+        file.extend_from_slice(&IEND);
+
+        assert!(matches!(
+            PngDecoder::read(file.as_slice()).map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::ChunkError(ChunkError::ChunkBeforeIHDR(chunk_type))) if &chunk_type == b"gAMA"
+        ));
     }
 }
@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::{BufWriter, Cursor, Read, Write};
 use std::path::Path;
@@ -7,21 +8,40 @@ use crc32fast::Hasher;
 use flate2::bufread::ZlibEncoder;
 use flate2::Compression;
 
-use crate::chunks::{Chunk, NamedChunk};
-use crate::common::{HEADER, IEND};
-use crate::errors::PngDecodingError;
+use crate::chunks::{Chunk, NamedChunk, IHDR};
+use crate::common::{ColorType, HEADER, IEND};
+use crate::errors::{PngDecodingError, PngEncodingError};
 use crate::png::Png;
 
 impl Png {
-    pub fn save<S: AsRef<Path>>(&self, file_path: S) -> Result<(), PngDecodingError> {
+    pub fn save<S: AsRef<Path>>(&self, file_path: S) -> Result<(), PngEncodingError> {
         let buffer = &mut BufWriter::new(File::create(file_path)?);
         self.write(buffer)?;
         Ok(())
     }
 
-    pub fn write<T: Write>(&self, buffer: &mut BufWriter<T>) -> Result<(), PngDecodingError> {
+    /// Encode the image and write it to `buffer`.
+    ///
+    /// The image is checked before anything is written, so an invalid image leaves
+    /// `buffer` untouched
+    pub fn write<T: Write>(&self, buffer: &mut BufWriter<T>) -> Result<(), PngEncodingError> {
+        // image data is always written without interlacing
+        let ihdr = IHDR::new(
+            self.ihdr.width,
+            self.ihdr.height,
+            self.ihdr.bit_depth,
+            self.ihdr.color_type,
+            self.ihdr.compression_type,
+            self.ihdr.filter_method,
+            0,
+        )?;
+        if ihdr.color_type == ColorType::Indexed && self.plte.is_none() {
+            return Err(PngEncodingError::PLTEChunkNotFound);
+        }
+        let data = self.data_chunk()?;
+
         buffer.write_all(&HEADER)?;
-        self.write_chunk(&self.ihdr, buffer)?;
+        self.write_chunk(&ihdr, buffer)?;
 
         if let Some(chrm) = &self.ancillary_chunks.chrm {
             self.write_chunk(chrm, buffer)?;
@@ -29,8 +49,16 @@ impl Png {
         if let Some(iccp) = &self.ancillary_chunks.iCCP {
             self.write_chunk(iccp, buffer)?;
         }
+        if let Some(plte) = &self.plte {
+            self.write_chunk(plte, buffer)?;
+        }
+        for chunk in self.custom_chunks.iter() {
+            let mut serialized = chunk.chunk_type().to_vec();
+            chunk.serialize(&mut serialized)?;
+            write_serialized(&serialized, buffer)?;
+        }
 
-        self.write_data(buffer)?;
+        self.write_chunk(&data, buffer)?;
 
         buffer.write_all(&IEND)?;
         Ok(())
@@ -40,46 +68,76 @@ impl Png {
         &self,
         chunk: &C,
         buffer: &mut BufWriter<T>,
-    ) -> Result<(), PngDecodingError> {
+    ) -> Result<(), PngEncodingError> {
         let mut serialized = Vec::with_capacity(4 + chunk.size_hint());
         serialized.extend_from_slice(&C::NAME);
-        chunk.serialize(&mut serialized);
+        chunk.serialize(&mut serialized)?;
 
-        let len = serialized.len() as u32 - 4;
-
-        buffer.write_all(&len.to_be_bytes())?;
-        buffer.write_all(&serialized)?;
+        write_serialized(&serialized, buffer)
+    }
 
-        let mut hasher = Hasher::new();
-        hasher.update(&serialized);
-        buffer.write_all(&hasher.finalize().to_be_bytes())?;
+    /// The image data to be written, decoding it first if needed
+    fn data_chunk(&self) -> Result<DataChunk<'_>, PngEncodingError> {
+        let raw_buffer = match self.decoded_buffer.as_deref() {
+            Some(buffer) => Cow::Borrowed(buffer),
+            None => Cow::Owned(self.decode()?.buffer),
+        };
 
-        Ok(())
-    }
+        let expected = self.output_buffer_size();
+        if raw_buffer.len() != expected {
+            return Err(PngEncodingError::InvalidBufferSize {
+                expected,
+                found: raw_buffer.len(),
+            });
+        }
 
-    fn write_data<T: Write>(&self, buffer: &mut BufWriter<T>) -> Result<(), PngDecodingError> {
-        let chunk = DataChunk {
-            width: self.width(),
+        Ok(DataChunk {
             height: self.height(),
             bpp: self.bpp(),
-            raw_buffer: self
-                .decoded_buffer
-                .as_deref()
-                .map(Cow::Borrowed)
-                .unwrap_or_else(|| Cow::Owned(self.decode().buffer)),
-        };
+            bytes_per_row: self.bytes_per_row(),
+            raw_buffer,
+        })
+    }
+}
 
-        self.write_chunk(&chunk, buffer)?;
+/// Write a chunk's length and CRC around its type and data
+fn write_serialized<T: Write>(
+    serialized: &[u8],
+    buffer: &mut BufWriter<T>,
+) -> Result<(), PngEncodingError> {
+    let (chunk_type, data) = serialized.split_at(4);
+    let len = chunk_length(chunk_type, data.len())?;
 
-        Ok(())
+    buffer.write_all(&len.to_be_bytes())?;
+    buffer.write_all(serialized)?;
+
+    let mut hasher = Hasher::new();
+    hasher.update(serialized);
+    buffer.write_all(&hasher.finalize().to_be_bytes())?;
+
+    Ok(())
+}
+
+/// The length to write for a chunk with `length` bytes of data
+fn chunk_length(chunk_type: &[u8], length: usize) -> Result<u32, PngEncodingError> {
+    match u32::try_from(length) {
+        Ok(len) if len <= i32::MAX as u32 => Ok(len),
+        _ => {
+            let mut ty = [0; 4];
+            ty.copy_from_slice(chunk_type);
+            Err(PngEncodingError::ChunkTooLarge {
+                chunk_type: ty,
+                length,
+            })
+        }
     }
 }
 
 struct DataChunk<'a> {
     raw_buffer: Cow<'a, [u8]>,
-    width: u32,
     height: u32,
     bpp: usize,
+    bytes_per_row: usize,
 }
 
 impl<'a> NamedChunk<'a> for DataChunk<'_> {
@@ -97,11 +155,15 @@ impl<'a> Chunk<'a> for DataChunk<'_> {
         todo!()
     }
 
-    fn serialize(&self, out_buffer: &mut Vec<u8>) {
+    fn serialize(&self, out_buffer: &mut Vec<u8>) -> Result<(), PngEncodingError> {
         let filtered = self.filter_image();
 
         let mut compressor = ZlibEncoder::new(Cursor::new(filtered), Compression::fast());
-        compressor.read_to_end(out_buffer).unwrap();
+        compressor
+            .read_to_end(out_buffer)
+            .map_err(PngEncodingError::CompressionError)?;
+
+        Ok(())
     }
 
     fn size_hint(&self) -> usize
@@ -114,14 +176,13 @@ impl<'a> Chunk<'a> for DataChunk<'_> {
 
 impl DataChunk<'_> {
     fn filter_image(&self) -> Vec<u8> {
-        let mut out =
-            vec![0; self.bpp * self.width as usize * self.height as usize + self.height as usize];
+        let mut out = vec![0; (self.bytes_per_row + 1) * self.height as usize];
 
         let mut offset = 0;
-        let up = vec![0; self.bpp * self.width as usize];
+        let up = vec![0; self.bytes_per_row];
         let mut up: &[u8] = &up;
 
-        for row in self.raw_buffer.chunks_exact(self.width as usize * self.bpp) {
+        for row in self.raw_buffer.chunks_exact(self.bytes_per_row) {
             self.filter_row(row, up, &mut out[offset..]);
             offset += row.len() + 1;
             up = row;
@@ -254,3 +315,74 @@ fn paeth_predictor(a: i16, b: i16, c: i16) -> u8 {
         (c % 256) as u8
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chunks::PLTE, decoder::PngDecoder, errors::MetadataError, PngBuilder};
+
+    fn encode(png: &Png) -> Result<Vec<u8>, PngEncodingError> {
+        let mut encoded = Vec::new();
+        png.write(&mut BufWriter::new(&mut encoded))?;
+        Ok(encoded)
+    }
+
+    #[test]
+    fn invalid_images_are_not_written() {
+        let png = PngBuilder::new(2, 2)
+            .color_type(ColorType::RGB)
+            .buffer(vec![0; 11])
+            .finish();
+        assert!(matches!(
+            encode(&png),
+            Err(PngEncodingError::InvalidBufferSize {
+                expected: 12,
+                found: 11
+            })
+        ));
+
+        let mut png = PngBuilder::new(1, 1)
+            .color_type(ColorType::RGB)
+            .buffer(vec![0; 3])
+            .finish();
+        png.ihdr.bit_depth = 4;
+        assert!(matches!(
+            encode(&png),
+            Err(PngEncodingError::MetadataError(
+                MetadataError::InvalidBitDepthForColorType { bit_depth: 4, .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn indexed_image_round_trip() {
+        let mut png = PngBuilder::new(3, 1)
+            .color_type(ColorType::Indexed)
+            .buffer(vec![2, 0, 1])
+            .finish();
+        assert!(matches!(
+            encode(&png),
+            Err(PngEncodingError::PLTEChunkNotFound)
+        ));
+
+        png.plte = Some(PLTE {
+            entries: vec![[1u8, 2, 3].into(), [4u8, 5, 6].into(), [7u8, 8, 9].into()],
+        });
+        let decoded = PngDecoder::read(encode(&png).unwrap().as_slice()).unwrap();
+        assert_eq!(decoded.plte, png.plte);
+        assert_eq!(decoded.decode().unwrap().buffer, vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn chunk_length_limit() {
+        assert_eq!(chunk_length(b"IDAT", 0).unwrap(), 0);
+        assert_eq!(chunk_length(b"IDAT", (1 << 31) - 1).unwrap(), (1 << 31) - 1);
+        assert!(matches!(
+            chunk_length(b"IDAT", 1 << 31),
+            Err(PngEncodingError::ChunkTooLarge {
+                chunk_type,
+                length,
+            }) if &chunk_type == b"IDAT" && length == 1 << 31
+        ));
+    }
+}
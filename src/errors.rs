@@ -1,6 +1,6 @@
-use std::{fmt, io};
+use std::{error::Error, fmt, io};
 
-use crate::common::ColorType;
+use crate::common::{ColorType, Rect};
 
 /// Container for errors that can occur when decoding a PNG
 #[derive(Debug)]
@@ -20,18 +20,194 @@ pub enum PngDecodingError {
     MetadataError(MetadataError),
     IoError(io::Error),
     ZeroLengthIDAT,
+    /// A scanline began with a filter type outside the range `0..=4`
+    InvalidFilterType(u8),
+    /// The decompressed image data ended before every scanline was read
+    NotEnoughImageData {
+        expected: usize,
+        found: usize,
+    },
+    /// The decompressed image data continued after every scanline was read
+    TooMuchImageData {
+        expected: usize,
+        found: usize,
+    },
+    /// The zlib stream formed by the IDAT chunks is corrupt
+    ZlibError(io::Error),
+    /// A pixel of an indexed image refers to a palette entry that does not exist
+    InvalidPaletteIndex {
+        index: u8,
+        palette_len: usize,
+    },
+    /// The region passed to `decode_rect` is empty or extends past the edges of the image
+    InvalidRegion(Rect),
+    /// The buffer passed to `decode_into` cannot hold the decoded image
+    OutputBufferTooSmall {
+        required: usize,
+        found: usize,
+    },
     Utf8Error(std::str::Utf8Error),
     StringDecodeError(std::string::FromUtf8Error),
     ChunkError(ChunkError),
+    LimitError(LimitError),
+    ICCProfileError(ICCProfileError),
+    /// An error from reading a file, along with where in the file it happened
+    Located {
+        location: ErrorLocation,
+        error: Box<PngDecodingError>,
+    },
+}
+
+impl PngDecodingError {
+    /// Where in the file the error happened, if it happened while reading one
+    pub fn location(&self) -> Option<&ErrorLocation> {
+        match self {
+            PngDecodingError::Located { location, .. } => Some(location),
+            _ => None,
+        }
+    }
+
+    /// The error itself, without its location
+    pub fn kind(&self) -> &PngDecodingError {
+        match self {
+            PngDecodingError::Located { error, .. } => error.kind(),
+            error => error,
+        }
+    }
+
+    /// The error itself, discarding its location
+    pub fn into_kind(self) -> PngDecodingError {
+        match self {
+            PngDecodingError::Located { error, .. } => error.into_kind(),
+            error => error,
+        }
+    }
+}
+
+/// The position in a file at which decoding failed
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct ErrorLocation {
+    /// The byte offset of the start of the chunk being read, or 0 for the signature
+    pub offset: u64,
+    /// The index of the chunk being read, counting from 0 for the IHDR chunk. `None`
+    /// while reading the signature
+    pub chunk_index: Option<usize>,
+    /// The type of the chunk being read. `None` if the chunk header could not be read
+    pub chunk_type: Option<[u8; 4]>,
+}
+
+impl fmt::Display for ErrorLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.chunk_index, self.chunk_type) {
+            (None, _) => write!(f, "signature"),
+            (Some(index), None) => write!(f, "chunk {} at byte {}", index, self.offset),
+            (Some(index), Some(chunk_type)) => write!(
+                f,
+                "'{}' chunk {} at byte {}",
+                String::from_utf8_lossy(&chunk_type),
+                index,
+                self.offset
+            ),
+        }
+    }
+}
+
+/// Errors raised when a file exceeds one of the configured `DecoderLimits`
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum LimitError {
+    /// The image is wider than `max_width`
+    WidthTooLarge { width: u32, max: u32 },
+    /// The image is taller than `max_height`
+    HeightTooLarge { height: u32, max: u32 },
+    /// The image has more than `max_pixels` pixels
+    TooManyPixels { pixels: u64, max: u64 },
+    /// A single chunk is longer than `max_chunk_size`
+    ChunkTooLarge {
+        chunk_type: [u8; 4],
+        length: u32,
+        max: u32,
+    },
+    /// The combined length of all metadata chunks is more than `max_metadata_bytes`
+    TooMuchMetadata { total: u64, max: u64 },
+    /// The decompressed image data would be longer than `max_decompressed_bytes`
+    DecompressedDataTooLarge { length: u64, max: u64 },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use LimitError::*;
+        match self {
+            WidthTooLarge { width, max } => {
+                write!(f, "image width {} exceeds the limit of {}", width, max)
+            }
+            HeightTooLarge { height, max } => {
+                write!(f, "image height {} exceeds the limit of {}", height, max)
+            }
+            TooManyPixels { pixels, max } => {
+                write!(
+                    f,
+                    "image has {} pixels, exceeding the limit of {}",
+                    pixels, max
+                )
+            }
+            ChunkTooLarge {
+                chunk_type,
+                length,
+                max,
+            } => {
+                write!(
+                    f,
+                    "'{}' chunk length {} exceeds the limit of {}",
+                    String::from_utf8_lossy(chunk_type),
+                    length,
+                    max
+                )
+            }
+            TooMuchMetadata { total, max } => {
+                write!(
+                    f,
+                    "{} bytes of metadata exceeds the limit of {}",
+                    total, max
+                )
+            }
+            DecompressedDataTooLarge { length, max } => {
+                write!(
+                    f,
+                    "{} bytes of decompressed image data exceeds the limit of {}",
+                    length, max
+                )
+            }
+        }
+    }
 }
 
 /// Errors dealing with critical and ancillary chunks
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum ChunkError {
+    /// The first chunk of the file was not an IHDR chunk
+    IHDRChunkNotFound,
+    /// A chunk appeared before the IHDR chunk
+    ChunkBeforeIHDR([u8; 4]),
+    /// A chunk that must come before the PLTE chunk came after it
+    ChunkAfterPLTE([u8; 4]),
+    /// A chunk that must come after the PLTE chunk came before it
+    ChunkBeforePLTE([u8; 4]),
+    /// A chunk that must come before the image data came after it
+    ChunkAfterIDAT([u8; 4]),
+    /// A chunk that may only appear once appeared again
+    DuplicateChunk([u8; 4]),
+    /// The IDAT chunks were separated by other chunks
+    NonConsecutiveIDAT,
+    /// A chunk of a type that the decoder options reject
+    RejectedChunk([u8; 4]),
     /// A PLTE chunk was found in a color type other than indexed, RBA, or RGBA
     UnexpectedPLTEChunk,
+    /// A tRNS chunk was found in an image that already has an alpha channel
+    UnexpectedtRNSChunk,
     /// A PLTE chunk was not found in an indexed color type context
     PLTEChunkNotFound,
+    /// The file ended without any IDAT chunks
+    IDATChunkNotFound,
     /// The length of the PLTE chunk found did not fit `len % 3 == 0`, so is potentially corrupted
     InvalidPLTELength,
     /// Attempted to access ICC profile; however, no ICCP chunk was found
@@ -42,6 +218,12 @@ pub enum ChunkError {
     UnrecognizedCriticalChunk([u8; 4]),
     /// An sRGB value outside the range `0..=3` was found
     UnrecognizedsRGBValue(u8),
+    /// The CRC stored after a chunk did not match the CRC computed from its type and data
+    CrcMismatch {
+        chunk_type: [u8; 4],
+        expected: u32,
+        found: u32,
+    },
 }
 
 impl fmt::Display for ChunkError {
@@ -55,12 +237,66 @@ impl fmt::Display for ChunkError {
                     String::from_utf8_lossy(name)
                 )
             }
+            IHDRChunkNotFound => {
+                write!(f, "the first chunk was not an IHDR chunk")
+            }
+            ChunkBeforeIHDR(name) => {
+                write!(
+                    f,
+                    "'{}' chunk found before the IHDR chunk",
+                    String::from_utf8_lossy(name)
+                )
+            }
+            ChunkAfterPLTE(name) => {
+                write!(
+                    f,
+                    "'{}' chunk found after the PLTE chunk",
+                    String::from_utf8_lossy(name)
+                )
+            }
+            ChunkBeforePLTE(name) => {
+                write!(
+                    f,
+                    "'{}' chunk found before the PLTE chunk",
+                    String::from_utf8_lossy(name)
+                )
+            }
+            ChunkAfterIDAT(name) => {
+                write!(
+                    f,
+                    "'{}' chunk found after the image data",
+                    String::from_utf8_lossy(name)
+                )
+            }
+            DuplicateChunk(name) => {
+                write!(
+                    f,
+                    "found more than one '{}' chunk",
+                    String::from_utf8_lossy(name)
+                )
+            }
+            NonConsecutiveIDAT => {
+                write!(f, "IDAT chunks were separated by other chunks")
+            }
+            RejectedChunk(chunk_type) => {
+                write!(
+                    f,
+                    "'{}' chunks are rejected by the decoder options",
+                    String::from_utf8_lossy(chunk_type)
+                )
+            }
             UnexpectedPLTEChunk => {
                 write!(f, "unexpected PLTE chunk found")
             }
+            UnexpectedtRNSChunk => {
+                write!(f, "tRNS chunk found in an image with an alpha channel")
+            }
             PLTEChunkNotFound => {
                 write!(f, "no PLTE chunk was found")
             }
+            IDATChunkNotFound => {
+                write!(f, "no IDAT chunk was found")
+            }
             InvalidPLTELength => {
                 write!(f, "PLTE chunk length was not divisible by 3 (and so doesn't properly give RGB values)")
             }
@@ -73,6 +309,124 @@ impl fmt::Display for ChunkError {
             UnrecognizedsRGBValue(val) => {
                 write!(f, "found {}, but expected value in 0..=3", val)
             }
+            CrcMismatch {
+                chunk_type,
+                expected,
+                found,
+            } => {
+                write!(
+                    f,
+                    "CRC mismatch in '{}' chunk: expected {:#010x}, but found {:#010x}",
+                    String::from_utf8_lossy(chunk_type),
+                    expected,
+                    found
+                )
+            }
+        }
+    }
+}
+
+/// Problems found while decoding that did not prevent the file from being read
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum DecodeWarning {
+    /// A chunk error that was tolerated because of the decoder options
+    ChunkError(ChunkError),
+    /// An ancillary chunk of an unknown type, with no policy or handler of its own,
+    /// was kept without being interpreted
+    UnrecognizedChunk([u8; 4]),
+    /// A chunk contained a value outside of the range allowed for this image, so the
+    /// chunk was ignored
+    ValueOutOfRange { chunk_type: [u8; 4], value: u32 },
+    /// Text that was not valid UTF-8, with the invalid sequences replaced by U+FFFD
+    InvalidText {
+        chunk_type: [u8; 4],
+        keyword: String,
+    },
+    /// Compressed text that could not be decompressed, so the chunk was ignored
+    InvalidCompressedText {
+        chunk_type: [u8; 4],
+        keyword: String,
+    },
+}
+
+impl fmt::Display for DecodeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeWarning::ChunkError(err) => write!(f, "{}", err),
+            DecodeWarning::UnrecognizedChunk(chunk_type) => write!(
+                f,
+                "unrecognized ancillary chunk '{}'",
+                String::from_utf8_lossy(chunk_type)
+            ),
+            DecodeWarning::ValueOutOfRange { chunk_type, value } => write!(
+                f,
+                "ignored '{}' chunk with out of range value {}",
+                String::from_utf8_lossy(chunk_type),
+                value
+            ),
+            DecodeWarning::InvalidText {
+                chunk_type,
+                keyword,
+            } => write!(
+                f,
+                "invalid UTF-8 in '{}' chunk with keyword '{}'",
+                String::from_utf8_lossy(chunk_type),
+                keyword
+            ),
+            DecodeWarning::InvalidCompressedText {
+                chunk_type,
+                keyword,
+            } => write!(
+                f,
+                "ignored '{}' chunk with keyword '{}' because its text could not be decompressed",
+                String::from_utf8_lossy(chunk_type),
+                keyword
+            ),
+        }
+    }
+}
+
+/// Errors in the structure of an embedded ICC profile
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum ICCProfileError {
+    /// The profile is too short to hold its header and tag count
+    TooShort { length: usize },
+    /// The profile's header claims more bytes than the iCCP chunk holds
+    Truncated { size: u32, length: usize },
+    /// The `acsp` signature was missing from the header
+    InvalidSignature([u8; 4]),
+    /// A tag's data extends past the end of the profile
+    TagOutOfBounds([u8; 4]),
+}
+
+impl fmt::Display for ICCProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ICCProfileError::*;
+        match self {
+            TooShort { length } => {
+                write!(f, "ICC profile of {} bytes is too short", length)
+            }
+            Truncated { size, length } => {
+                write!(
+                    f,
+                    "ICC profile of {} bytes was cut short after {} bytes",
+                    size, length
+                )
+            }
+            InvalidSignature(found) => {
+                write!(
+                    f,
+                    "expected ICC profile signature 'acsp', but found '{}'",
+                    String::from_utf8_lossy(found)
+                )
+            }
+            TagOutOfBounds(tag) => {
+                write!(
+                    f,
+                    "ICC profile tag '{}' extends past the end of the profile",
+                    String::from_utf8_lossy(tag)
+                )
+            }
         }
     }
 }
@@ -94,6 +448,8 @@ pub enum MetadataError {
     InvalidWidth { width: usize },
     /// Height was not in range `1..=2**31`
     InvalidHeight { height: usize },
+    /// The requested operation is not supported for images of this bit depth
+    UnsupportedBitDepth { bit_depth: u8 },
     /// An invalid bit depth and color type combination was found
     InvalidBitDepthForColorType {
         bit_depth: u8,
@@ -142,6 +498,9 @@ impl fmt::Display for MetadataError {
             InvalidHeight { height } => {
                 write!(f, "expected height in 1..=2**31, but found {}", height)
             }
+            UnsupportedBitDepth { bit_depth } => {
+                write!(f, "operation not supported for bit depth {}", bit_depth)
+            }
             InvalidBitDepthForColorType {
                 bit_depth,
                 color_type,
@@ -174,6 +533,51 @@ impl fmt::Display for PngDecodingError {
             ZeroLengthIDAT => {
                 write!(f, "no pixel data provided")
             }
+            InvalidFilterType(filter_type) => {
+                write!(
+                    f,
+                    "expected filter type in 0..=4, but found {}",
+                    filter_type
+                )
+            }
+            NotEnoughImageData { expected, found } => {
+                write!(
+                    f,
+                    "expected {} bytes of decompressed image data, but found only {}",
+                    expected, found
+                )
+            }
+            TooMuchImageData { expected, found } => {
+                write!(
+                    f,
+                    "expected {} bytes of decompressed image data, but found {}",
+                    expected, found
+                )
+            }
+            ZlibError(..) => {
+                write!(f, "invalid zlib stream")
+            }
+            InvalidPaletteIndex { index, palette_len } => {
+                write!(
+                    f,
+                    "palette index {} is out of range for a palette of {} entries",
+                    index, palette_len
+                )
+            }
+            InvalidRegion(rect) => {
+                write!(
+                    f,
+                    "region of {}x{} pixels at ({}, {}) is empty or outside of the image",
+                    rect.width, rect.height, rect.x, rect.y
+                )
+            }
+            OutputBufferTooSmall { required, found } => {
+                write!(
+                    f,
+                    "output buffer of {} bytes is too small for an image of {} bytes",
+                    found, required
+                )
+            }
             StringDecodeError(err) => {
                 write!(f, "{}", err)
             }
@@ -183,10 +587,48 @@ impl fmt::Display for PngDecodingError {
             ChunkError(err) => {
                 write!(f, "{}", err)
             }
+            LimitError(err) => {
+                write!(f, "{}", err)
+            }
+            ICCProfileError(err) => {
+                write!(f, "{}", err)
+            }
+            Located { location, .. } => {
+                write!(f, "failed to decode {}", location)
+            }
         }
     }
 }
 
+impl Error for PngDecodingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        use PngDecodingError::*;
+        match self {
+            ZlibError(err) => Some(err),
+            Located { error, .. } => Some(error.as_ref()),
+            // these display the error they wrap, so skip straight to its source
+            MetadataError(err) => err.source(),
+            IoError(err) => err.source(),
+            Utf8Error(err) => err.source(),
+            StringDecodeError(err) => err.source(),
+            ChunkError(err) => err.source(),
+            LimitError(err) => err.source(),
+            ICCProfileError(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl Error for LimitError {}
+
+impl Error for ChunkError {}
+
+impl Error for MetadataError {}
+
+impl Error for ICCProfileError {}
+
+impl Error for DecodeWarning {}
+
 macro_rules! convert_to_decoding_error {
     ($val:ident) => {
         impl std::convert::From<$val> for PngDecodingError {
@@ -206,9 +648,103 @@ macro_rules! convert_to_decoding_error {
 
 convert_to_decoding_error!(MetadataError);
 convert_to_decoding_error!(ChunkError);
+convert_to_decoding_error!(LimitError);
+convert_to_decoding_error!(ICCProfileError);
 convert_to_decoding_error!(IoError, io::Error);
 convert_to_decoding_error!(Utf8Error, std::str::Utf8Error);
 convert_to_decoding_error!(StringDecodeError, std::string::FromUtf8Error);
 
+/// Container for errors that can occur when encoding a PNG
 #[derive(Debug)]
-pub enum PngEncodingError {}
+pub enum PngEncodingError {
+    /// The length of the image buffer does not match the dimensions and pixel format
+    /// of the image
+    InvalidBufferSize {
+        expected: usize,
+        found: usize,
+    },
+    /// The IHDR chunk describes an image that cannot be encoded, such as one with a
+    /// bit depth that is not allowed for its color type
+    MetadataError(MetadataError),
+    /// An indexed image has no PLTE chunk
+    PLTEChunkNotFound,
+    /// A chunk's data is longer than the 2^31 - 1 bytes a PNG chunk can hold
+    ChunkTooLarge {
+        chunk_type: [u8; 4],
+        length: usize,
+    },
+    /// The image data of a decoded file could not be decoded in order to be re-encoded
+    DecodingError(PngDecodingError),
+    IoError(io::Error),
+    /// The image data could not be compressed
+    CompressionError(io::Error),
+}
+
+impl fmt::Display for PngEncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use PngEncodingError::*;
+        match self {
+            InvalidBufferSize { expected, found } => {
+                write!(
+                    f,
+                    "expected an image buffer of {} bytes, but found {}",
+                    expected, found
+                )
+            }
+            MetadataError(err) => {
+                write!(f, "{}", err)
+            }
+            PLTEChunkNotFound => {
+                write!(f, "indexed image has no palette")
+            }
+            ChunkTooLarge { chunk_type, length } => {
+                write!(
+                    f,
+                    "{} chunk of {} bytes is too large to be written",
+                    String::from_utf8_lossy(chunk_type),
+                    length
+                )
+            }
+            DecodingError(..) => {
+                write!(f, "failed to decode the image data to be encoded")
+            }
+            IoError(err) => {
+                write!(f, "{}", err)
+            }
+            CompressionError(..) => {
+                write!(f, "failed to compress the image data")
+            }
+        }
+    }
+}
+
+impl Error for PngEncodingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        use PngEncodingError::*;
+        match self {
+            DecodingError(err) => Some(err),
+            CompressionError(err) => Some(err),
+            MetadataError(err) => err.source(),
+            IoError(err) => err.source(),
+            InvalidBufferSize { .. } | PLTEChunkNotFound | ChunkTooLarge { .. } => None,
+        }
+    }
+}
+
+impl From<MetadataError> for PngEncodingError {
+    fn from(error: MetadataError) -> Self {
+        PngEncodingError::MetadataError(error)
+    }
+}
+
+impl From<PngDecodingError> for PngEncodingError {
+    fn from(error: PngDecodingError) -> Self {
+        PngEncodingError::DecodingError(error)
+    }
+}
+
+impl From<io::Error> for PngEncodingError {
+    fn from(error: io::Error) -> Self {
+        PngEncodingError::IoError(error)
+    }
+}
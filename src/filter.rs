@@ -1,3 +1,5 @@
+use crate::errors::PngDecodingError;
+
 pub fn up(prev: &[u8], raw_row: &[u8], decoded_row: &mut [u8]) {
     if prev.is_empty() {
         decoded_row[..].copy_from_slice(raw_row);
@@ -30,13 +32,8 @@ pub fn average(prev: &[u8], raw_row: &[u8], decoded_row: &mut [u8], bytes_per_pi
         let up = prev[i];
         let left = decoded_row[i - bytes_per_pixel];
 
-        let val2 = ((u16::from(up) + u16::from(left)) / 2) as u8;
         let val = (up >> 1).wrapping_add(left >> 1) + (up & left & 0b1);
 
-        if val2 != val {
-            println!("{up}:{left}:{val2}:{val}");
-        }
-
         decoded_row[i] = raw_row[i].wrapping_add(val);
     }
 }
@@ -63,6 +60,68 @@ pub fn paeth(prev: &[u8], raw_row: &[u8], decoded_row: &mut [u8], bytes_per_pixe
     }
 }
 
+/// Reverse the filter applied to a single scanline.
+///
+/// `prev` is the previously unfiltered scanline, and must be the same length as
+/// `raw_row`. For the first scanline of an image (or of an interlaced pass), it
+/// should be all zeroes
+pub fn unfilter_row(
+    filter_type: u8,
+    prev: &[u8],
+    raw_row: &[u8],
+    decoded_row: &mut [u8],
+    bytes_per_pixel: usize,
+) -> Result<(), PngDecodingError> {
+    match filter_type {
+        0 => decoded_row.copy_from_slice(raw_row),
+        1 => sub(raw_row, decoded_row, bytes_per_pixel),
+        2 => up(prev, raw_row, decoded_row),
+        3 => average(prev, raw_row, decoded_row, bytes_per_pixel),
+        4 => paeth(prev, raw_row, decoded_row, bytes_per_pixel),
+        _ => return Err(PngDecodingError::InvalidFilterType(filter_type)),
+    }
+
+    Ok(())
+}
+
+/// Unfilter `height` consecutive scanlines, each prefixed by its filter type byte.
+///
+/// `bytes_per_row` excludes the filter type byte, and `data` must contain at least
+/// `(bytes_per_row + 1) * height` bytes. Returns the unfiltered scanlines packed
+/// together without filter bytes
+pub(crate) fn unfilter_scanlines(
+    data: &[u8],
+    bytes_per_row: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+) -> Result<Vec<u8>, PngDecodingError> {
+    let mut decoded = vec![0; bytes_per_row * height];
+    let zeroes = vec![0; bytes_per_row];
+
+    for (i, raw_row) in data
+        .chunks_exact(bytes_per_row + 1)
+        .take(height)
+        .enumerate()
+    {
+        let (prev, decoded_row) = decoded.split_at_mut(i * bytes_per_row);
+        let prev = if i == 0 {
+            &zeroes
+        } else {
+            &prev[(i - 1) * bytes_per_row..]
+        };
+
+        unfilter_row(
+            raw_row[0],
+            prev,
+            &raw_row[1..],
+            &mut decoded_row[..bytes_per_row],
+            bytes_per_pixel,
+        )?;
+    }
+
+    Ok(decoded)
+}
+
 // a = left, b = above, c = upper left
 fn paeth_predictor(a: i16, b: i16, c: i16) -> u8 {
     let p = a + b - c;
@@ -89,4 +148,16 @@ mod tests {
         assert_eq!(paeth_predictor(118, 128, 125), 118);
         assert_eq!(paeth_predictor(37, 84, 61), 61);
     }
+
+    #[test]
+    fn average_does_not_overflow() {
+        for up in 0..=255u8 {
+            for left in 0..=255u8 {
+                let mut decoded_row = [0, 0];
+                average(&[0, up], &[left, 0], &mut decoded_row, 1);
+                let expected = (u16::from(up) + u16::from(left)) / 2;
+                assert_eq!(u16::from(decoded_row[1]), expected);
+            }
+        }
+    }
 }
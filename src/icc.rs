@@ -0,0 +1,449 @@
+//! Parsing of the ICC profiles embedded in iCCP chunks
+//!
+//! Only the header, the tag table and the handful of tags needed to identify a color
+//! space are interpreted. The raw bytes of any other tag are available from `tag_data`
+
+use std::convert::{TryFrom, TryInto};
+
+use crate::{chunks::sRGB, errors::ICCProfileError};
+
+/// The length of the header at the start of every profile
+const HEADER_LEN: usize = 128;
+
+/// The version of the ICC specification a profile follows
+#[derive(Debug, Default, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ICCVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub bugfix: u8,
+}
+
+/// An entry in a profile's tag table
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct ICCTag {
+    pub signature: [u8; 4],
+    /// The offset of the tag's data from the start of the profile
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// A CIE XYZ color, as stored in `XYZ ` tags
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct XYZ {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+/// A tone reproduction curve, mapping device values to linear light
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    /// Values are already linear
+    Identity,
+    /// Values are raised to this power
+    Gamma(f64),
+    /// Evenly spaced samples of the curve, to be interpolated between
+    Table(Vec<u16>),
+    /// One of the parametric functions of a `para` tag, with its parameters in the
+    /// order given by the ICC specification
+    Parametric {
+        function_type: u16,
+        params: Vec<f64>,
+    },
+}
+
+/// An ICC profile, describing the color space of the image
+#[derive(Default, Debug, Clone, Hash, PartialEq, Eq)]
+pub struct ICCProfile {
+    icc_profile: Vec<u8>,
+    tags: Vec<ICCTag>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn read_signature(data: &[u8], offset: usize) -> Option<[u8; 4]> {
+    data.get(offset..offset.checked_add(4)?)?.try_into().ok()
+}
+
+/// Read an `s15Fixed16Number`
+fn read_fixed(data: &[u8], offset: usize) -> Option<f64> {
+    Some(f64::from(read_u32(data, offset)? as i32) / 65536.0)
+}
+
+impl ICCProfile {
+    /// Parse the header and tag table of an uncompressed profile
+    pub fn parse(icc_profile: Vec<u8>) -> Result<Self, ICCProfileError> {
+        let length = icc_profile.len();
+        if length < HEADER_LEN + 4 {
+            return Err(ICCProfileError::TooShort { length });
+        }
+
+        let size = read_u32(&icc_profile, 0).unwrap_or(0);
+        if size as usize > length {
+            return Err(ICCProfileError::Truncated { size, length });
+        }
+
+        let signature = read_signature(&icc_profile, 36).unwrap_or_default();
+        if &signature != b"acsp" {
+            return Err(ICCProfileError::InvalidSignature(signature));
+        }
+
+        let tag_count = read_u32(&icc_profile, HEADER_LEN).unwrap_or(0) as usize;
+        // every tag takes 12 bytes of the table, so this bounds the allocation
+        let mut tags = Vec::with_capacity(std::cmp::min(tag_count, length / 12));
+
+        for i in 0..tag_count {
+            let entry = HEADER_LEN + 4 + i * 12;
+            let tag = match (
+                read_signature(&icc_profile, entry),
+                read_u32(&icc_profile, entry + 4),
+                read_u32(&icc_profile, entry + 8),
+            ) {
+                (Some(signature), Some(offset), Some(size)) => ICCTag {
+                    signature,
+                    offset,
+                    size,
+                },
+                _ => return Err(ICCProfileError::TooShort { length }),
+            };
+
+            let end = u64::from(tag.offset) + u64::from(tag.size);
+            if end > length as u64 {
+                return Err(ICCProfileError::TagOutOfBounds(tag.signature));
+            }
+
+            tags.push(tag);
+        }
+
+        Ok(ICCProfile { icc_profile, tags })
+    }
+
+    /// The uncompressed profile
+    pub fn data(&self) -> &[u8] {
+        &self.icc_profile
+    }
+
+    pub fn version(&self) -> ICCVersion {
+        let (major, minor) = match self.icc_profile.get(8..10) {
+            Some(&[major, minor]) => (major, minor),
+            _ => (0, 0),
+        };
+
+        ICCVersion {
+            major,
+            minor: minor >> 4,
+            bugfix: minor & 0xf,
+        }
+    }
+
+    /// The kind of device the profile describes, such as `mntr` for a display or `scnr`
+    /// for an input device
+    pub fn device_class(&self) -> [u8; 4] {
+        read_signature(&self.icc_profile, 12).unwrap_or_default()
+    }
+
+    /// The color space of the image data, such as `RGB ` or `GRAY`
+    pub fn color_space(&self) -> [u8; 4] {
+        read_signature(&self.icc_profile, 16).unwrap_or_default()
+    }
+
+    /// The profile connection space, either `XYZ ` or `Lab `
+    pub fn pcs(&self) -> [u8; 4] {
+        read_signature(&self.icc_profile, 20).unwrap_or_default()
+    }
+
+    /// The rendering intent, which uses the same values as the sRGB chunk. `None` if
+    /// the value is out of range
+    pub fn rendering_intent(&self) -> Option<sRGB> {
+        let intent = read_u32(&self.icc_profile, 64)?;
+        sRGB::from_u8(u8::try_from(intent).ok()?).ok()
+    }
+
+    /// The profile's tag table, in the order it is stored
+    pub fn tags(&self) -> &[ICCTag] {
+        &self.tags
+    }
+
+    /// The data of the tag with the given signature, starting with its type signature
+    pub fn tag_data(&self, signature: &[u8; 4]) -> Option<&[u8]> {
+        let tag = self.tags.iter().find(|tag| &tag.signature == signature)?;
+        let start = tag.offset as usize;
+        self.icc_profile.get(start..(start + tag.size as usize))
+    }
+
+    /// The text of a `desc`, `mluc` or `text` tag. For `mluc` tags, holding text in
+    /// several languages, this is the first translation
+    pub fn text(&self, signature: &[u8; 4]) -> Option<String> {
+        let data = self.tag_data(signature)?;
+
+        match &read_signature(data, 0)? {
+            b"desc" => {
+                let count = read_u32(data, 8)? as usize;
+                let ascii = data.get(12..12usize.checked_add(count)?)?;
+                Some(nul_terminated(ascii))
+            }
+            b"text" => Some(nul_terminated(data.get(8..)?)),
+            b"mluc" => {
+                if read_u32(data, 8)? == 0 {
+                    return None;
+                }
+                let length = read_u32(data, 20)? as usize;
+                let offset = read_u32(data, 24)? as usize;
+                let utf16: Vec<u16> = data
+                    .get(offset..offset.checked_add(length)?)?
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect();
+                Some(String::from_utf16_lossy(&utf16))
+            }
+            _ => None,
+        }
+    }
+
+    /// The profile description, which is how profiles normally identify themselves,
+    /// such as "sRGB IEC61966-2.1" or "Display P3"
+    pub fn description(&self) -> Option<String> {
+        self.text(b"desc")
+    }
+
+    /// The first color of an `XYZ ` tag
+    pub fn xyz(&self, signature: &[u8; 4]) -> Option<XYZ> {
+        let data = self.tag_data(signature)?;
+        if &read_signature(data, 0)? != b"XYZ " {
+            return None;
+        }
+
+        Some(XYZ {
+            x: read_fixed(data, 8)?,
+            y: read_fixed(data, 12)?,
+            z: read_fixed(data, 16)?,
+        })
+    }
+
+    /// The media white point, from the `wtpt` tag
+    pub fn white_point(&self) -> Option<XYZ> {
+        self.xyz(b"wtpt")
+    }
+
+    /// The XYZ color of the red primary, from the `rXYZ` tag
+    pub fn red_colorant(&self) -> Option<XYZ> {
+        self.xyz(b"rXYZ")
+    }
+
+    /// The XYZ color of the green primary, from the `gXYZ` tag
+    pub fn green_colorant(&self) -> Option<XYZ> {
+        self.xyz(b"gXYZ")
+    }
+
+    /// The XYZ color of the blue primary, from the `bXYZ` tag
+    pub fn blue_colorant(&self) -> Option<XYZ> {
+        self.xyz(b"bXYZ")
+    }
+
+    /// The curve of a `curv` or `para` tag
+    pub fn curve(&self, signature: &[u8; 4]) -> Option<Curve> {
+        let data = self.tag_data(signature)?;
+
+        match &read_signature(data, 0)? {
+            b"curv" => match read_u32(data, 8)? {
+                0 => Some(Curve::Identity),
+                // a single u8Fixed8Number
+                1 => Some(Curve::Gamma(f64::from(read_u16(data, 12)?) / 256.0)),
+                count => (0..count as usize)
+                    .map(|i| read_u16(data, 12 + i * 2))
+                    .collect::<Option<_>>()
+                    .map(Curve::Table),
+            },
+            b"para" => {
+                let function_type = read_u16(data, 8)?;
+                let count = match function_type {
+                    0 => 1,
+                    1 => 3,
+                    2 => 4,
+                    3 => 5,
+                    4 => 7,
+                    _ => return None,
+                };
+                let params = (0..count)
+                    .map(|i| read_fixed(data, 12 + i * 4))
+                    .collect::<Option<_>>()?;
+                Some(Curve::Parametric {
+                    function_type,
+                    params,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn red_trc(&self) -> Option<Curve> {
+        self.curve(b"rTRC")
+    }
+
+    pub fn green_trc(&self) -> Option<Curve> {
+        self.curve(b"gTRC")
+    }
+
+    pub fn blue_trc(&self) -> Option<Curve> {
+        self.curve(b"bTRC")
+    }
+
+    /// The curve of grayscale profiles, from the `kTRC` tag
+    pub fn gray_trc(&self) -> Option<Curve> {
+        self.curve(b"kTRC")
+    }
+}
+
+/// Text up to the first nul byte, or all of it if there is none
+fn nul_terminated(text: &[u8]) -> String {
+    let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+    String::from_utf8_lossy(&text[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::ColorType,
+        decoder::PngDecoder,
+        errors::{ChunkError, PngDecodingError},
+        test_utils,
+    };
+
+    /// A minimal version 4 display profile with the given tags
+    fn profile(tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut header = vec![0; HEADER_LEN];
+        header[8] = 4;
+        header[9] = 0x30;
+        header[12..16].copy_from_slice(b"mntr");
+        header[16..20].copy_from_slice(b"RGB ");
+        header[20..24].copy_from_slice(b"XYZ ");
+        header[36..40].copy_from_slice(b"acsp");
+        header[67] = 1;
+
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = Vec::new();
+        let mut offset = HEADER_LEN + 4 + tags.len() * 12;
+        for (signature, tag) in tags {
+            table.extend_from_slice(*signature);
+            table.extend_from_slice(&(offset as u32).to_be_bytes());
+            table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
+            offset += tag.len();
+        }
+
+        let mut out = header;
+        out.extend(table);
+        out.extend(data);
+        let size = (out.len() as u32).to_be_bytes();
+        out[..4].copy_from_slice(&size);
+        out
+    }
+
+    fn fixed(value: f64) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    #[test]
+    fn parse_display_p3_profile() {
+        let mut mluc = b"mluc\0\0\0\0".to_vec();
+        mluc.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 12]);
+        mluc.extend_from_slice(b"enUS");
+        mluc.extend_from_slice(&[0, 0, 0, 20, 0, 0, 0, 28]);
+        mluc.extend("Display P3".encode_utf16().flat_map(u16::to_be_bytes));
+
+        let mut red = b"XYZ \0\0\0\0".to_vec();
+        red.extend(fixed(0.515_1));
+        red.extend(fixed(0.241_2));
+        red.extend(fixed(-0.001_1));
+
+        let mut trc = b"para\0\0\0\0\0\x03\0\0".to_vec();
+        for param in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.040_45].iter() {
+            trc.extend(fixed(*param));
+        }
+
+        let data = profile(&[(b"desc", mluc), (b"rXYZ", red), (b"rTRC", trc)]);
+        let icc = ICCProfile::parse(data).unwrap();
+
+        let version = icc.version();
+        assert_eq!((version.major, version.minor, version.bugfix), (4, 3, 0));
+        assert_eq!(&icc.device_class(), b"mntr");
+        assert_eq!(&icc.color_space(), b"RGB ");
+        assert_eq!(&icc.pcs(), b"XYZ ");
+        assert_eq!(icc.rendering_intent(), Some(sRGB::RelativeColorimetric));
+        assert_eq!(icc.tags().len(), 3);
+
+        assert_eq!(icc.description().as_deref(), Some("Display P3"));
+        let red = icc.red_colorant().unwrap();
+        assert!((red.x - 0.5151).abs() < 1e-4 && (red.z + 0.0011).abs() < 1e-4);
+        assert!(icc.green_colorant().is_none());
+        match icc.red_trc() {
+            Some(Curve::Parametric {
+                function_type: 3,
+                params,
+            }) => assert!((params[0] - 2.4).abs() < 1e-4),
+            other => panic!("unexpected curve {:?}", other),
+        }
+    }
+
+    #[test]
+    fn profile_from_iccp_chunk() {
+        let mut desc = b"desc\0\0\0\0\0\0\0\x0aAdobe RGB\0".to_vec();
+        desc.extend_from_slice(&[0; 8]);
+        let mut iccp = b"Adobe\0\0".to_vec();
+        iccp.extend(test_utils::zlib(&profile(&[(b"desc", desc)])));
+
+        let file = test_utils::png(
+            1,
+            1,
+            8,
+            ColorType::RGB,
+            &[test_utils::chunk(b"iCCP", &iccp)],
+            &[0, 0, 0],
+        );
+        let png = PngDecoder::read(file.as_slice()).unwrap();
+        let icc = png.iccp_profile().unwrap();
+        assert_eq!(icc.description().as_deref(), Some("Adobe RGB"));
+
+        let file = test_utils::png(1, 1, 8, ColorType::RGB, &[], &[0, 0, 0]);
+        let png = PngDecoder::read(file.as_slice()).unwrap();
+        assert!(matches!(
+            png.iccp_profile(),
+            Err(PngDecodingError::ChunkError(ChunkError::ICCPChunkNotFound))
+        ));
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        let valid = profile(&[(b"wtpt", b"XYZ \0\0\0\0".to_vec())]);
+        assert!(ICCProfile::parse(valid.clone()).is_ok());
+
+        assert_eq!(
+            ICCProfile::parse(valid[..100].to_vec()),
+            Err(ICCProfileError::TooShort { length: 100 })
+        );
+
+        let mut bad_signature = valid.clone();
+        bad_signature[36] = b'x';
+        assert_eq!(
+            ICCProfile::parse(bad_signature),
+            Err(ICCProfileError::InvalidSignature(*b"xcsp"))
+        );
+
+        // the tag's data claims to run past the end of the profile
+        let mut bad_tag = valid;
+        bad_tag[HEADER_LEN + 4 + 11] = 200;
+        assert_eq!(
+            ICCProfile::parse(bad_tag),
+            Err(ICCProfileError::TagOutOfBounds(*b"wtpt"))
+        );
+    }
+}
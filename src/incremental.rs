@@ -0,0 +1,498 @@
+use crc32fast::Hasher;
+
+use crate::{
+    chunks::{AncillaryChunks, IHDR, PLTE},
+    common::HEADER,
+    decoder::{check_header, check_iend, DecoderOptions, DecoderState},
+    errors::{DecodeWarning, PngDecodingError},
+    scanlines::{Row, ScanlineDecoder},
+    Png,
+};
+
+/// Progress reported by an `IncrementalDecoder` as data is fed to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeEvent<'a> {
+    /// The IHDR chunk was parsed, so the dimensions and color type of the image are known
+    Header(IHDR),
+    /// A chunk other than IHDR, IDAT or IEND was parsed. Its contents are available
+    /// through the decoder
+    Chunk([u8; 4]),
+    /// A row of the image was decoded. Rows of interlaced images are reported pass by pass
+    Row(Row<'a>),
+    /// Every row of the image has been decoded
+    ImageComplete,
+    /// The IEND chunk was reached, so the file is complete
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Signature,
+    ChunkHeader,
+    /// Collecting the data and CRC of a chunk other than IDAT
+    ChunkData {
+        length: u32,
+        chunk_type: [u8; 4],
+    },
+    /// Passing the data of an IDAT chunk to the inflater
+    ImageData {
+        remaining: u32,
+    },
+    /// Hashing the data of a chunk that is not processed, without storing it
+    SkippedData {
+        remaining: u32,
+        chunk_type: [u8; 4],
+    },
+    /// Waiting for the CRC of an IDAT or skipped chunk, whose data has been hashed
+    /// as it arrived
+    StreamedCrc {
+        chunk_type: [u8; 4],
+    },
+    End,
+}
+
+/// A decoder driven by pushing data to it, for when a file arrives in pieces.
+///
+/// Partial chunks are kept between calls to `feed`, and events are reported as soon
+/// as enough data is available. Image data is inflated as it arrives rather than
+/// being buffered, and only the current and previous rows are held in memory. Once
+/// `feed` returns an error, the decoder should not be used again
+#[derive(Debug)]
+pub struct IncrementalDecoder {
+    state: State,
+    decoder: DecoderState,
+    scanlines: Option<ScanlineDecoder>,
+    /// Bytes of a signature, chunk header, CRC or chunk that has only partially arrived
+    pending: Vec<u8>,
+    /// CRC of the current IDAT chunk
+    hasher: Hasher,
+}
+
+impl Default for IncrementalDecoder {
+    fn default() -> Self {
+        IncrementalDecoder::new()
+    }
+}
+
+impl IncrementalDecoder {
+    pub fn new() -> Self {
+        IncrementalDecoder::with_options(&DecoderOptions::default())
+    }
+
+    pub fn with_options(options: &DecoderOptions) -> Self {
+        IncrementalDecoder {
+            state: State::Signature,
+            decoder: DecoderState::new(options),
+            scanlines: None,
+            pending: Vec::new(),
+            hasher: Hasher::new(),
+        }
+    }
+
+    pub fn ihdr(&self) -> &IHDR {
+        &self.decoder.ihdr
+    }
+
+    pub fn palette(&self) -> Option<&PLTE> {
+        self.decoder.plte.as_ref()
+    }
+
+    pub fn ancillary_chunks(&self) -> &AncillaryChunks {
+        &self.decoder.ancillary_chunks
+    }
+
+    pub fn warnings(&self) -> &[DecodeWarning] {
+        &self.decoder.warnings
+    }
+
+    /// Whether the IEND chunk has been reached
+    pub fn is_finished(&self) -> bool {
+        self.state == State::End
+    }
+
+    /// The file's metadata, without any image data
+    pub fn into_png(self) -> Png {
+        self.decoder.into_png(Vec::new())
+    }
+
+    /// Take bytes from `data` until `pending` holds `len` bytes.
+    ///
+    /// Returns whether `pending` is complete
+    fn fill_pending(&mut self, data: &mut &[u8], len: usize) -> bool {
+        let needed = len - self.pending.len();
+        let taken = std::cmp::min(needed, data.len());
+        self.pending.extend_from_slice(&data[..taken]);
+        *data = &data[taken..];
+        self.pending.len() == len
+    }
+
+    /// Decode as much as possible of the next piece of the file, calling `on_event`
+    /// as progress is made
+    pub fn feed(
+        &mut self,
+        data: &[u8],
+        on_event: impl FnMut(DecodeEvent<'_>),
+    ) -> Result<(), PngDecodingError> {
+        match self.feed_data(data, on_event) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(self.decoder.locate(err)),
+        }
+    }
+
+    fn feed_data(
+        &mut self,
+        mut data: &[u8],
+        mut on_event: impl FnMut(DecodeEvent<'_>),
+    ) -> Result<(), PngDecodingError> {
+        while !data.is_empty() {
+            match self.state {
+                State::Signature => {
+                    let complete = self.fill_pending(&mut data, HEADER.len());
+                    // fail as soon as a byte differs, the rest of the header is left as zeros
+                    if self.pending[..] != HEADER[..self.pending.len()] {
+                        let mut header = [0; 8];
+                        header[..self.pending.len()].copy_from_slice(&self.pending);
+                        check_header(header)?;
+                    }
+                    if !complete {
+                        break;
+                    }
+                    self.pending.clear();
+                    self.state = State::ChunkHeader;
+                }
+                State::ChunkHeader => {
+                    if self.pending.is_empty() {
+                        self.decoder.start_chunk();
+                    }
+                    if !self.fill_pending(&mut data, 8) {
+                        break;
+                    }
+                    let length = u32::from_be_bytes([
+                        self.pending[0],
+                        self.pending[1],
+                        self.pending[2],
+                        self.pending[3],
+                    ]);
+                    let chunk_type = [
+                        self.pending[4],
+                        self.pending[5],
+                        self.pending[6],
+                        self.pending[7],
+                    ];
+                    self.pending.clear();
+
+                    self.decoder.check_length(length, chunk_type)?;
+                    let accepted = self.decoder.check_order(chunk_type)?;
+
+                    self.hasher = Hasher::new();
+                    self.hasher.update(&chunk_type);
+
+                    if !accepted {
+                        self.state = State::SkippedData {
+                            remaining: length,
+                            chunk_type,
+                        };
+                    } else if &chunk_type == b"IDAT" {
+                        if self.scanlines.is_none() {
+                            self.scanlines = Some(ScanlineDecoder::new(&self.decoder.ihdr)?);
+                        }
+                        self.state = State::ImageData { remaining: length };
+                    } else {
+                        if &chunk_type == b"IEND" {
+                            self.end_image_data(&mut on_event)?;
+                        }
+                        self.state = State::ChunkData { length, chunk_type };
+                    }
+                }
+                State::ChunkData { length, chunk_type } => {
+                    if !self.fill_pending(&mut data, length as usize + 4) {
+                        break;
+                    }
+                    let (chunk_data, crc) = self.pending.split_at(length as usize);
+                    let crc = u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]);
+                    self.decoder.check_chunk_crc(chunk_type, chunk_data, crc)?;
+
+                    if &chunk_type == b"IEND" {
+                        check_iend(length, crc)?;
+                        self.pending.clear();
+                        self.state = State::End;
+                        on_event(DecodeEvent::End);
+                        break;
+                    }
+
+                    let chunk_data = std::mem::take(&mut self.pending);
+                    self.decoder
+                        .process_chunk(chunk_type, &chunk_data[..length as usize])?;
+                    self.state = State::ChunkHeader;
+
+                    if &chunk_type == b"IHDR" {
+                        on_event(DecodeEvent::Header(self.decoder.ihdr));
+                    } else {
+                        on_event(DecodeEvent::Chunk(chunk_type));
+                    }
+                }
+                State::ImageData { remaining } => {
+                    let len = std::cmp::min(data.len(), remaining as usize);
+                    let (input, rest) = data.split_at(len);
+                    data = rest;
+
+                    if self.decoder.checks_crc() {
+                        self.hasher.update(input);
+                    }
+                    self.inflate(input, &mut on_event)?;
+
+                    self.state = match remaining - len as u32 {
+                        0 => State::StreamedCrc {
+                            chunk_type: *b"IDAT",
+                        },
+                        remaining => State::ImageData { remaining },
+                    };
+                }
+                State::SkippedData {
+                    remaining,
+                    chunk_type,
+                } => {
+                    let len = std::cmp::min(data.len(), remaining as usize);
+                    let (input, rest) = data.split_at(len);
+                    data = rest;
+
+                    if self.decoder.checks_crc() {
+                        self.hasher.update(input);
+                    }
+
+                    self.state = match remaining - len as u32 {
+                        0 => State::StreamedCrc { chunk_type },
+                        remaining => State::SkippedData {
+                            remaining,
+                            chunk_type,
+                        },
+                    };
+                }
+                State::StreamedCrc { chunk_type } => {
+                    if !self.fill_pending(&mut data, 4) {
+                        break;
+                    }
+                    let crc = u32::from_be_bytes([
+                        self.pending[0],
+                        self.pending[1],
+                        self.pending[2],
+                        self.pending[3],
+                    ]);
+                    self.pending.clear();
+
+                    let hasher = std::mem::replace(&mut self.hasher, Hasher::new());
+                    self.decoder.check_crc(chunk_type, hasher.finalize(), crc)?;
+                    self.state = State::ChunkHeader;
+                }
+                // anything after IEND is ignored
+                State::End => break,
+            }
+        }
+
+        // a chunk that ends exactly at the end of `data` has nothing left to read, but
+        // its CRC is still needed
+        match self.state {
+            State::ImageData { remaining: 0 } => {
+                self.state = State::StreamedCrc {
+                    chunk_type: *b"IDAT",
+                }
+            }
+            State::SkippedData {
+                remaining: 0,
+                chunk_type,
+            } => self.state = State::StreamedCrc { chunk_type },
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Pass compressed image data to the inflater, reporting every row it completes
+    fn inflate(
+        &mut self,
+        mut input: &[u8],
+        on_event: &mut impl FnMut(DecodeEvent<'_>),
+    ) -> Result<(), PngDecodingError> {
+        let scanlines = match self.scanlines.as_mut() {
+            Some(scanlines) => scanlines,
+            None => return Ok(()),
+        };
+
+        // rows can be held by the inflater even once all of the input is consumed
+        while !scanlines.is_finished() {
+            let (consumed, ready) = scanlines.decompress(input, false)?;
+            input = &input[consumed..];
+
+            if ready {
+                on_event(DecodeEvent::Row(scanlines.take_row()?));
+                if scanlines.is_finished() {
+                    on_event(DecodeEvent::ImageComplete);
+                }
+            } else if consumed == 0 {
+                break;
+            }
+        }
+
+        // whatever follows the last row must not decompress to anything
+        if scanlines.is_finished() {
+            scanlines.check_end(input)?;
+        }
+
+        Ok(())
+    }
+
+    /// Called when the IEND chunk begins. If image data has been seen, it is now
+    /// complete, so any rows still held by the inflater are reported
+    fn end_image_data(
+        &mut self,
+        on_event: &mut impl FnMut(DecodeEvent<'_>),
+    ) -> Result<(), PngDecodingError> {
+        let scanlines = match self.scanlines.as_mut() {
+            Some(scanlines) => scanlines,
+            None => return Ok(()),
+        };
+
+        while !scanlines.is_finished() {
+            let (_, ready) = scanlines.decompress(&[], true)?;
+            if ready {
+                on_event(DecodeEvent::Row(scanlines.take_row()?));
+                if scanlines.is_finished() {
+                    on_event(DecodeEvent::ImageComplete);
+                }
+            }
+        }
+
+        scanlines.check_end(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{common::ColorType, decoder::ChunkPolicy, errors::ChunkError, test_utils};
+
+    /// Feed `file` in pieces of `size` bytes, collecting a description of each event
+    fn feed_in_pieces(file: &[u8], size: usize) -> (IncrementalDecoder, Vec<String>) {
+        let mut decoder = IncrementalDecoder::new();
+        let mut events = Vec::new();
+        for piece in file.chunks(size) {
+            decoder
+                .feed(piece, |event| {
+                    events.push(match event {
+                        DecodeEvent::Header(ihdr) => {
+                            format!("header {}x{}", ihdr.width, ihdr.height)
+                        }
+                        DecodeEvent::Chunk(chunk_type) => {
+                            format!("chunk {}", String::from_utf8_lossy(&chunk_type))
+                        }
+                        DecodeEvent::Row(row) => format!("row {} {:?}", row.y, row.data),
+                        DecodeEvent::ImageComplete => "complete".to_owned(),
+                        DecodeEvent::End => "end".to_owned(),
+                    })
+                })
+                .unwrap();
+        }
+        (decoder, events)
+    }
+
+    #[test]
+    fn events_are_independent_of_piece_size() {
+        let file = test_utils::png(
+            3,
+            2,
+            8,
+            ColorType::Grayscale,
+            &[test_utils::chunk(b"tEXt", b"Title\0pieces")],
+            &[1, 2, 3, 4, 5, 6],
+        );
+
+        let expected = vec![
+            "header 3x2",
+            "chunk tEXt",
+            "row 0 [1, 2, 3]",
+            "row 1 [4, 5, 6]",
+            "complete",
+            "end",
+        ];
+
+        for size in [1, 2, 7, file.len()] {
+            let (decoder, events) = feed_in_pieces(&file, size);
+            assert_eq!(events, expected, "pieces of {} bytes", size);
+            assert!(decoder.is_finished());
+            assert_eq!(decoder.ancillary_chunks().tEXt[0].text, "pieces");
+        }
+    }
+
+    #[test]
+    fn ignored_chunks_are_hashed_but_not_stored() {
+        let mut text = test_utils::chunk(b"tEXt", b"Title\0ignored");
+        let file = test_utils::png(1, 1, 8, ColorType::Grayscale, &[text.clone()], &[9]);
+        let options = DecoderOptions::new().chunk_policy(*b"tEXt", ChunkPolicy::Ignore);
+
+        let mut decoder = IncrementalDecoder::with_options(&options);
+        let mut chunks = 0;
+        for piece in file.chunks(3) {
+            decoder
+                .feed(piece, |event| {
+                    if let DecodeEvent::Chunk(_) = event {
+                        chunks += 1;
+                    }
+                })
+                .unwrap();
+        }
+        assert!(decoder.is_finished());
+        assert_eq!(chunks, 0);
+        assert!(decoder.ancillary_chunks().tEXt.is_empty());
+
+        let last = text.len() - 1;
+        text[last] ^= 1;
+        let file = test_utils::png(1, 1, 8, ColorType::Grayscale, &[text], &[9]);
+        let mut decoder = IncrementalDecoder::with_options(&options);
+        assert!(matches!(
+            decoder
+                .feed(&file, |_| {})
+                .map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::ChunkError(ChunkError::CrcMismatch { .. }))
+        ));
+    }
+
+    #[test]
+    fn excess_image_data() {
+        // a 1x1 image needs 2 bytes of image data, including the filter type byte
+        let mut file = HEADER.to_vec();
+        file.extend(test_utils::ihdr(1, 1, 8, ColorType::Grayscale));
+        file.extend(test_utils::chunk(b"IDAT", &test_utils::zlib(&[0, 1, 2, 3])));
+        file.extend(test_utils::chunk(b"IEND", &[]));
+
+        for size in [1, 7, file.len()].iter() {
+            let mut decoder = IncrementalDecoder::new();
+            let result = file
+                .chunks(*size)
+                .try_for_each(|piece| decoder.feed(piece, |_| {}));
+            assert!(matches!(
+                result.map_err(PngDecodingError::into_kind),
+                Err(PngDecodingError::TooMuchImageData { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn invalid_signature_is_reported_early() {
+        let mut decoder = IncrementalDecoder::new();
+        assert!(decoder.feed(&HEADER[..4], |_| {}).is_ok());
+        assert!(matches!(
+            decoder
+                .feed(b"GIF89a", |_| {})
+                .map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::InvalidHeader { .. })
+        ));
+
+        let mut decoder = IncrementalDecoder::new();
+        assert!(matches!(
+            decoder
+                .feed(b"G", |_| {})
+                .map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::InvalidHeader { .. })
+        ));
+    }
+}
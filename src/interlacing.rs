@@ -1,3 +1,205 @@
-pub fn decode_adam7(_buffer: &[u8]) -> Vec<u8> {
-    todo!("interlacing unimplemented")
+use crate::{errors::PngDecodingError, filter};
+
+/// `(x_start, y_start, x_step, y_step)` for each of the seven Adam7 passes
+pub(crate) const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// The width and height in pixels of the reduced image for the given pass.
+///
+/// Either may be 0 for small images, in which case the pass is empty and
+/// contributes no bytes (not even filter type bytes) to the image data
+pub(crate) const fn pass_dimensions(pass: usize, width: usize, height: usize) -> (usize, usize) {
+    let (x_start, y_start, x_step, y_step) = ADAM7_PASSES[pass];
+
+    let pass_width = if width > x_start {
+        (width - x_start).div_ceil(x_step)
+    } else {
+        0
+    };
+    let pass_height = if height > y_start {
+        (height - y_start).div_ceil(y_step)
+    } else {
+        0
+    };
+
+    (pass_width, pass_height)
+}
+
+/// Number of bytes in an unfiltered scanline `width` pixels wide, excluding the filter type byte
+pub(crate) const fn bytes_per_row(width: usize, bits_per_pixel: usize) -> usize {
+    (width * bits_per_pixel).div_ceil(8)
+}
+
+/// The number of bytes of decompressed image data in an Adam7 image, including filter type bytes.
+///
+/// Saturates at `usize::MAX` rather than overflowing
+pub(crate) fn adam7_data_len(width: usize, height: usize, bits_per_pixel: usize) -> usize {
+    (0..ADAM7_PASSES.len())
+        .map(|pass| match pass_dimensions(pass, width, height) {
+            (0, _) | (_, 0) => 0,
+            (pass_width, pass_height) => {
+                (bytes_per_row(pass_width, bits_per_pixel) + 1).saturating_mul(pass_height)
+            }
+        })
+        .fold(0, usize::saturating_add)
+}
+
+/// Deinterlace an inflated Adam7 image.
+///
+/// `buffer` is the decompressed contents of the IDAT chunks, and must contain at least
+/// `adam7_data_len(width, height, bits_per_pixel)` bytes. Each pass is unfiltered
+/// independently, and its pixels are scattered into their final positions. The
+/// returned buffer contains `height` unfiltered, row-major scanlines, each
+/// `bytes_per_row(width, bits_per_pixel)` bytes long. Sub-byte samples remain packed
+pub fn decode_adam7(
+    buffer: &[u8],
+    width: usize,
+    height: usize,
+    bits_per_pixel: usize,
+) -> Result<Vec<u8>, PngDecodingError> {
+    let stride = bytes_per_row(width, bits_per_pixel);
+    let bytes_per_pixel = std::cmp::max(1, bits_per_pixel / 8);
+
+    let mut out = vec![0; stride * height];
+    let mut offset = 0;
+
+    for pass in 0..ADAM7_PASSES.len() {
+        let (pass_width, pass_height) = pass_dimensions(pass, width, height);
+        if pass_width == 0 || pass_height == 0 {
+            continue;
+        }
+
+        let pass_stride = bytes_per_row(pass_width, bits_per_pixel);
+        let pass_len = (pass_stride + 1) * pass_height;
+
+        let pass_buffer = filter::unfilter_scanlines(
+            &buffer[offset..(offset + pass_len)],
+            pass_stride,
+            pass_height,
+            bytes_per_pixel,
+        )?;
+        offset += pass_len;
+
+        for (pass_y, pass_row) in pass_buffer.chunks_exact(pass_stride).enumerate() {
+            let y = image_y(pass, pass_y);
+            let row = &mut out[(y * stride)..((y + 1) * stride)];
+            scatter_row(pass, pass_row, pass_width, row, bits_per_pixel);
+        }
+    }
+
+    Ok(out)
+}
+
+/// The row of the full image that row `pass_y` of the reduced image for `pass` belongs to
+pub(crate) const fn image_y(pass: usize, pass_y: usize) -> usize {
+    let (_, y_start, _, y_step) = ADAM7_PASSES[pass];
+    y_start + pass_y * y_step
+}
+
+/// The column of the full image that column `pass_x` of the reduced image for `pass` belongs to
+pub(crate) const fn image_x(pass: usize, pass_x: usize) -> usize {
+    let (x_start, _, x_step, _) = ADAM7_PASSES[pass];
+    x_start + pass_x * x_step
+}
+
+/// Copy each pixel of a row of the reduced image for `pass` to its position in the
+/// corresponding row of the full image
+pub(crate) fn scatter_row(
+    pass: usize,
+    pass_row: &[u8],
+    pass_width: usize,
+    row: &mut [u8],
+    bits_per_pixel: usize,
+) {
+    for pass_x in 0..pass_width {
+        copy_pixel(pass_row, pass_x, row, image_x(pass, pass_x), bits_per_pixel);
+    }
+}
+
+/// Copy the pixel at index `from_x` of `from` to index `to_x` of `to`
+pub(crate) fn copy_pixel(
+    from: &[u8],
+    from_x: usize,
+    to: &mut [u8],
+    to_x: usize,
+    bits_per_pixel: usize,
+) {
+    if bits_per_pixel >= 8 {
+        let bytes_per_pixel = bits_per_pixel / 8;
+        let from_start = from_x * bytes_per_pixel;
+        let to_start = to_x * bytes_per_pixel;
+
+        to[to_start..(to_start + bytes_per_pixel)]
+            .copy_from_slice(&from[from_start..(from_start + bytes_per_pixel)]);
+        return;
+    }
+
+    // sub-byte pixels are packed starting from the most significant bit
+    let mask = (1u8 << bits_per_pixel) - 1;
+
+    let from_bit = from_x * bits_per_pixel;
+    let from_shift = 8 - bits_per_pixel - (from_bit % 8);
+    let sample = (from[from_bit / 8] >> from_shift) & mask;
+
+    let to_bit = to_x * bits_per_pixel;
+    let to_shift = 8 - bits_per_pixel - (to_bit % 8);
+    let to_byte = &mut to[to_bit / 8];
+    *to_byte = (*to_byte & !(mask << to_shift)) | (sample << to_shift);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::encode_adam7;
+
+    fn round_trip(width: usize, height: usize, bits_per_pixel: usize) {
+        let stride = bytes_per_row(width, bits_per_pixel);
+        let mut image: Vec<u8> = (0..(stride * height))
+            .map(|i| (i * 37 + 11) as u8)
+            .collect();
+
+        // padding bits at the end of each row are not part of any pixel
+        let used_bits = (width * bits_per_pixel) % 8;
+        if used_bits != 0 {
+            for row in image.chunks_exact_mut(stride) {
+                row[stride - 1] &= !(0xff >> used_bits);
+            }
+        }
+
+        let interlaced = encode_adam7(&image, width, height, bits_per_pixel);
+        assert_eq!(
+            interlaced.len(),
+            adam7_data_len(width, height, bits_per_pixel)
+        );
+
+        assert_eq!(
+            decode_adam7(&interlaced, width, height, bits_per_pixel).unwrap(),
+            image
+        );
+    }
+
+    #[test]
+    fn pass_dimensions_of_small_images() {
+        assert_eq!(pass_dimensions(0, 1, 1), (1, 1));
+        assert_eq!(pass_dimensions(1, 1, 1), (0, 1));
+        assert_eq!(pass_dimensions(6, 1, 1), (1, 0));
+        assert_eq!(pass_dimensions(0, 32, 32), (4, 4));
+        assert_eq!(pass_dimensions(6, 32, 32), (32, 16));
+    }
+
+    #[test]
+    fn adam7_round_trip() {
+        for &bits_per_pixel in &[1, 2, 4, 8, 16, 24, 32, 48, 64] {
+            for &(width, height) in &[(1, 1), (3, 5), (8, 8), (13, 7), (32, 32)] {
+                round_trip(width, height, bits_per_pixel);
+            }
+        }
+    }
 }
@@ -3,15 +3,40 @@
 #![warn(missing_debug_implementations)]
 
 pub use crate::common::*;
-pub use crate::decoder::PngDecoder;
+pub use crate::context::DecodeContext;
+pub use crate::custom::{CustomChunk, CustomChunks};
+pub use crate::decoder::{
+    ChunkOrder, ChunkPolicy, CrcCheck, DecoderLimits, DecoderOptions, PngDecoder, PngInfo,
+    ProbeStop,
+};
 pub use crate::filter::*;
+pub use icc::{Curve, ICCProfile, ICCTag, ICCVersion, XYZ};
+pub use incremental::{DecodeEvent, IncrementalDecoder};
 pub use png::{Png, PngBuilder};
+pub use png_ref::{ChunkRef, PngRef};
+pub use recover::{Recovered, RecoveryReport};
+pub use scanlines::Row;
+pub use stream::StreamingDecoder;
+pub use transform::Transformations;
 
+#[cfg(feature = "async")]
+mod async_io;
 pub mod chunks;
 mod common;
+mod context;
+mod custom;
 mod decoder;
 mod encoder;
 pub mod errors;
 mod filter;
+mod icc;
+mod incremental;
 mod interlacing;
 mod png;
+mod png_ref;
+mod recover;
+mod scanlines;
+mod stream;
+#[cfg(test)]
+mod test_utils;
+mod transform;
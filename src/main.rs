@@ -1,16 +1,29 @@
-use std::convert::TryInto;
-
-use rpng::{errors::PngDecodingError, Bitmap, Png, PngBuilder};
+use std::{convert::TryInto, error::Error};
+
+use rpng::{errors::PngDecodingError, Bitmap, ColorType, Png, PngBuilder, Transformations};
+
+/// Decode any image as 8-bit RGBA
+fn rgba8() -> Transformations {
+    Transformations::new()
+        .expand_palette(true)
+        .trns_to_alpha(true)
+        .gray_to_rgb(true)
+        .add_alpha(true)
+        .strip_16(true)
+}
 
-fn main() -> Result<(), PngDecodingError> {
+fn main() -> Result<(), Box<dyn Error>> {
     let png = Png::open(std::env::args().nth(1).unwrap())?;
 
-    let resized = resize_png(&png);
+    let resized = resize_png(&png)?;
 
     convert_to_ascii(&Bitmap {
         width: resized.width(),
         height: resized.height(),
+        color_type: resized.ihdr.color_type,
         bpp: resized.bpp(),
+        bit_depth: resized.ihdr.bit_depth,
+        packed: false,
         buffer: resized.idat.clone(),
     });
 
@@ -20,6 +33,8 @@ fn main() -> Result<(), PngDecodingError> {
 }
 
 fn convert_to_ascii(bitmap: &Bitmap) {
+    assert_eq!((bitmap.color_type, bitmap.bit_depth), (ColorType::RGBA, 8));
+
     let mut out = Vec::new();
 
     for row in bitmap.rows() {
@@ -54,12 +69,12 @@ fn convert_to_ascii(bitmap: &Bitmap) {
 
 const RESIZE_FACTOR: i32 = 3;
 
-fn resize_png(png: &Png) -> Png {
-    let bitmap = png.decode();
+fn resize_png(png: &Png) -> Result<Png, PngDecodingError> {
+    let bitmap = png.decode_with(rgba8())?;
 
-    let width = png.width();
+    let width = bitmap.width;
 
-    let bpp = png.bpp();
+    let bpp = bitmap.bpp;
 
     let new_width = png.width() as usize / RESIZE_FACTOR as usize;
     let new_height = png.height() as usize / RESIZE_FACTOR as usize;
@@ -81,10 +96,10 @@ fn resize_png(png: &Png) -> Png {
         }
     }
 
-    PngBuilder::new(new_width as u32, new_height as u32)
-        .color_type(png.ihdr.color_type)
+    Ok(PngBuilder::new(new_width as u32, new_height as u32)
+        .color_type(bitmap.color_type)
         .buffer(out.clone())
-        .finish()
+        .finish())
 }
 
 fn avg_channel(bitmap: &Bitmap, x: i32, y: i32, bpp: i32, width: i32, bpp_offset: i32) -> u8 {
@@ -1,18 +1,23 @@
 use std::{
+    cmp::Ordering,
     fmt,
-    fs::{self, File},
+    fs::File,
     io::{BufReader, Read},
+    ops::Range,
     path::Path,
 };
 
 use flate2::bufread::ZlibDecoder;
 
 use crate::{
-    chunks::{pHYs, AncillaryChunks, ICCProfile, Unit, UnrecognizedChunk, IHDR, PLTE},
-    common::{Bitmap, ColorType, DPI},
-    decoder::PngDecoder,
-    errors::{ChunkError, PngDecodingError},
-    filter,
+    chunks::{pHYs, sRGB, AncillaryChunks, ICCProfile, Unit, UnrecognizedChunk, IHDR, PLTE},
+    common::{Bitmap, ColorType, Rect, DPI},
+    context::DecodeContext,
+    custom::CustomChunks,
+    decoder::{DecoderOptions, PngDecoder, PngInfo, ProbeStop},
+    errors::{ChunkError, DecodeWarning, MetadataError, PngDecodingError},
+    filter, interlacing,
+    transform::{self, Transformations},
 };
 
 #[derive(Default, Clone, Hash, PartialEq, Eq)]
@@ -24,6 +29,11 @@ pub struct Png {
     pub decoded_buffer: Option<Vec<u8>>,
     pub unrecognized_chunks: Vec<UnrecognizedChunk>,
     pub ancillary_chunks: AncillaryChunks,
+    /// Chunks parsed by the handlers in the decoder options. These are written out
+    /// when the image is saved
+    pub custom_chunks: CustomChunks,
+    /// Problems tolerated while decoding the file
+    pub warnings: Vec<DecodeWarning>,
 }
 
 impl fmt::Debug for Png {
@@ -34,62 +44,150 @@ impl fmt::Debug for Png {
             .field("data", &format!("{} bytes (compressed)", self.idat.len()))
             .field("unrecognized_chunks", &self.unrecognized_chunks)
             .field("ancillary_chunks", &self.ancillary_chunks)
+            .field("custom_chunks", &self.custom_chunks)
+            .field("warnings", &self.warnings)
             .finish()
     }
 }
 
 impl Png {
     pub fn open(file_path: impl AsRef<Path>) -> Result<Self, PngDecodingError> {
-        let file_size: usize = fs::metadata(&file_path)?.len() as usize;
-        PngDecoder::read(BufReader::with_capacity(file_size, File::open(file_path)?))
+        PngDecoder::read(BufReader::new(File::open(file_path)?))
     }
 
-    pub fn decode(&self) -> Bitmap {
-        let mut decompressed_buffer = Vec::new();
-        let mut zlib = ZlibDecoder::new(&self.idat as &[u8]);
-        let buf_len = zlib.read_to_end(&mut decompressed_buffer).unwrap();
-        assert_ne!(buf_len, 0, "zero length idat");
+    pub fn open_with_options(
+        file_path: impl AsRef<Path>,
+        options: &DecoderOptions,
+    ) -> Result<Self, PngDecodingError> {
+        PngDecoder::read_with_options(BufReader::new(File::open(file_path)?), options)
+    }
 
-        let width = self.ihdr.width as usize;
-        let height = self.ihdr.height as usize;
+    /// Read the metadata at the start of a file, without reading its image data
+    pub fn probe(
+        file_path: impl AsRef<Path>,
+        stop: ProbeStop,
+    ) -> Result<PngInfo, PngDecodingError> {
+        PngDecoder::probe(BufReader::new(File::open(file_path)?), stop)
+    }
 
-        let mut decoded_buffer = vec![0; decompressed_buffer.len() - height];
+    pub fn decode(&self) -> Result<Bitmap, PngDecodingError> {
+        self.decode_with(Transformations::default())
+    }
 
-        let bytes_per_row = 1 + width * self.bpp();
+    pub fn decode_with(
+        &self,
+        transformations: Transformations,
+    ) -> Result<Bitmap, PngDecodingError> {
+        transform::apply(
+            transformations,
+            self.unfilter()?,
+            &self.ihdr,
+            self.plte.as_ref(),
+            self.ancillary_chunks.tRNS.as_ref(),
+            self.gamma(),
+        )
+    }
 
-        for i in 0..height {
-            let raw_row_start = i * bytes_per_row;
-            let decoded_row_start = raw_row_start - i;
-            let start = decompressed_buffer[raw_row_start];
-            let raw_row =
-                &decompressed_buffer[(raw_row_start + 1)..(raw_row_start + bytes_per_row)];
+    /// The number of bytes needed to hold the output of `decode_into`
+    pub fn output_buffer_size(&self) -> usize {
+        self.bytes_per_row() * self.ihdr.height as usize
+    }
 
-            let (prev, decoded_row) = decoded_buffer.split_at_mut(decoded_row_start);
+    /// Decode the image into the start of `out`, rather than allocating a new buffer.
+    ///
+    /// The output is laid out in the same way as the buffer returned by `decode`. To also
+    /// avoid allocating scratch space for each image, use a `DecodeContext`
+    pub fn decode_into(&self, out: &mut [u8]) -> Result<(), PngDecodingError> {
+        DecodeContext::new().decode_into(self, out)
+    }
 
-            let decoded_row = &mut decoded_row[..(bytes_per_row - 1)];
+    /// Decode only the pixels within `rect`, without inflating any more of the image
+    /// data than is needed. See `DecodeContext::decode_rect`
+    pub fn decode_rect(&self, rect: Rect) -> Result<Bitmap, PngDecodingError> {
+        DecodeContext::new().decode_rect(self, rect)
+    }
 
-            let prev = &prev[(prev.len().saturating_sub(bytes_per_row - 1))..];
+    /// Decode only the full-width rows in `rows`
+    pub fn decode_rows(&self, rows: Range<u32>) -> Result<Bitmap, PngDecodingError> {
+        let rect = Rect::new(
+            0,
+            rows.start,
+            self.width(),
+            rows.end.saturating_sub(rows.start),
+        );
+        self.decode_rect(rect)
+    }
 
-            if i != 0 {
-                debug_assert_eq!(prev.len(), raw_row.len());
-                debug_assert_eq!(prev.len(), decoded_row.len());
+    /// Decode a 16-bit image into samples in native endianness.
+    ///
+    /// Returns `Err(UnsupportedBitDepth)` if the bit depth is not 16
+    pub fn decode_u16(&self) -> Result<Vec<u16>, PngDecodingError> {
+        if self.ihdr.bit_depth != 16 {
+            return Err(MetadataError::UnsupportedBitDepth {
+                bit_depth: self.ihdr.bit_depth,
             }
+            .into());
+        }
+
+        Ok(self
+            .unfilter()?
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect())
+    }
+
+    /// Inflate and unfilter the image data, removing interlacing if present
+    fn unfilter(&self) -> Result<Vec<u8>, PngDecodingError> {
+        if self.ihdr.width == 0 {
+            return Err(MetadataError::InvalidWidth { width: 0 }.into());
+        }
+
+        if self.ihdr.height == 0 {
+            return Err(MetadataError::InvalidHeight { height: 0 }.into());
+        }
+
+        if self.idat.is_empty() {
+            return Err(PngDecodingError::ZeroLengthIDAT);
+        }
+
+        let expected = self.ihdr.image_data_len();
+
+        // inflating one byte past the expected length is enough to detect excess data,
+        // and stops a small file from decompressing to an arbitrarily large buffer
+        let mut decompressed_buffer = Vec::new();
+        ZlibDecoder::new(&self.idat as &[u8])
+            .take((expected as u64).saturating_add(1))
+            .read_to_end(&mut decompressed_buffer)
+            .map_err(PngDecodingError::ZlibError)?;
 
-            match start {
-                0 => decoded_row[..raw_row.len()].copy_from_slice(&raw_row),
-                1 => filter::sub(raw_row, decoded_row, self.bpp()),
-                2 => filter::up(prev, raw_row, decoded_row),
-                3 => filter::average(prev, raw_row, decoded_row, self.bpp()),
-                4 => filter::paeth(prev, raw_row, decoded_row, self.bpp()),
-                _ => unimplemented!("{}", start),
+        let found = decompressed_buffer.len();
+
+        match found.cmp(&expected) {
+            Ordering::Less => return Err(PngDecodingError::NotEnoughImageData { expected, found }),
+            Ordering::Greater => {
+                return Err(PngDecodingError::TooMuchImageData { expected, found })
             }
+            Ordering::Equal => {}
         }
 
-        Bitmap {
-            width: self.ihdr.width,
-            height: self.ihdr.height,
-            bpp: self.bpp(),
-            buffer: decoded_buffer,
+        let width = self.ihdr.width as usize;
+        let height = self.ihdr.height as usize;
+
+        match self.ihdr.interlace_method {
+            // adam7
+            1 => interlacing::decode_adam7(
+                &decompressed_buffer,
+                width,
+                height,
+                self.bits_per_pixel(),
+            ),
+            // no interlacing or invalid value
+            _ => filter::unfilter_scanlines(
+                &decompressed_buffer,
+                self.bytes_per_row(),
+                height,
+                self.bpp(),
+            ),
         }
     }
 
@@ -112,24 +210,32 @@ impl Png {
         }
     }
 
+    /// Decompress and parse the ICC profile in the iCCP chunk
     pub fn iccp_profile(&self) -> Result<ICCProfile, PngDecodingError> {
         let iccp = match self.ancillary_chunks.iCCP.as_ref() {
             Some(x) => x,
-            None => return Err(ChunkError::PLTEChunkNotFound.into()),
+            None => return Err(ChunkError::ICCPChunkNotFound.into()),
         };
         let mut zlib = ZlibDecoder::new(iccp.compressed_profile.as_slice());
         let mut buffer: Vec<u8> = Vec::new();
-        zlib.read_to_end(&mut buffer)?;
+        zlib.read_to_end(&mut buffer)
+            .map_err(PngDecodingError::ZlibError)?;
 
-        todo!()
+        Ok(ICCProfile::parse(buffer)?)
+    }
+
+    /// The gamma of the image, from its sRGB chunk or otherwise its gAMA chunk
+    pub fn gamma(&self) -> Option<f64> {
+        let gama = match self.ancillary_chunks.sRGB {
+            Some(_) => sRGB::GAMMA,
+            None => self.ancillary_chunks.gama?,
+        };
+        Some(gama.value())
     }
 
     pub fn dpi(&self) -> Option<DPI> {
         let meters_to_inch = 0.0254;
-        let phys: &pHYs = match self.ancillary_chunks.pHYs.as_ref() {
-            Some(x) => x,
-            None => return None,
-        };
+        let phys: &pHYs = self.ancillary_chunks.pHYs.as_ref()?;
         if phys.unit == Unit::Unknown {
             return None;
         }
@@ -147,11 +253,22 @@ impl Png {
     }
 
     /// `bpp` is defined as the number of bytes per complete pixel, rounding up to 1
+    ///
+    /// This is also the distance used by filters to find the corresponding byte of the previous pixel
     pub fn bpp(&self) -> usize {
-        std::cmp::max(
-            1,
-            ((self.ihdr.bit_depth / 8) * self.ihdr.color_type.channels()) as usize,
-        )
+        std::cmp::max(1, self.bits_per_pixel() / 8)
+    }
+
+    /// The number of bits per complete pixel
+    pub fn bits_per_pixel(&self) -> usize {
+        self.ihdr.bits_per_pixel()
+    }
+
+    /// The number of bytes in an unfiltered scanline, not including the filter type byte.
+    ///
+    /// Samples narrower than 8 bits are packed, so this may be less than `width * bpp`
+    pub fn bytes_per_row(&self) -> usize {
+        interlacing::bytes_per_row(self.ihdr.width as usize, self.bits_per_pixel())
     }
 }
 
@@ -208,6 +325,226 @@ impl PngBuilder {
             idat: self.buffer,
             unrecognized_chunks: Vec::new(),
             ancillary_chunks: AncillaryChunks::new(),
+            custom_chunks: CustomChunks::new(),
+            warnings: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn decode_packed_grayscale() {
+        // 5 pixels of 2 bits each occupy 2 bytes per row
+        let image = [0b0001_1011, 0b1100_0000, 0b1110_0100, 0b0000_0000];
+        let file = test_utils::png(5, 2, 2, ColorType::Grayscale, &[], &image);
+        let png = PngDecoder::read(file.as_slice()).unwrap();
+
+        assert_eq!(png.bytes_per_row(), 2);
+
+        let bitmap = png.decode().unwrap();
+        assert_eq!(bitmap.bit_depth, 2);
+        assert!(bitmap.packed);
+        assert_eq!(bitmap.buffer, image);
+        assert_eq!(bitmap.rows().count(), 2);
+        assert_eq!(
+            bitmap,
+            Bitmap::new(5, 2, ColorType::Grayscale, 2, image.to_vec()).unwrap()
+        );
+
+        let bitmap = png
+            .decode_with(Transformations::new().unpack(true))
+            .unwrap();
+        assert!(!bitmap.packed);
+        assert_eq!(bitmap.buffer, vec![0, 1, 2, 3, 3, 3, 2, 1, 0, 0]);
+    }
+
+    #[test]
+    fn expand_indexed_image() {
+        let plte = test_utils::chunk(b"PLTE", &[10, 11, 12, 20, 21, 22, 30, 31, 32]);
+        let expand = Transformations::new().expand_palette(true);
+
+        // 3 pixels of 2 bits each, referring to entries 2, 0 and 1
+        let file = test_utils::png(
+            3,
+            1,
+            2,
+            ColorType::Indexed,
+            std::slice::from_ref(&plte),
+            &[0b1000_0100],
+        );
+        let bitmap = PngDecoder::read(file.as_slice())
+            .unwrap()
+            .decode_with(expand)
+            .unwrap();
+        assert_eq!((bitmap.bpp, bitmap.bit_depth), (3, 8));
+        assert_eq!(bitmap.buffer, vec![30, 31, 32, 10, 11, 12, 20, 21, 22]);
+
+        // entries without a tRNS entry are opaque
+        let trns = test_utils::chunk(b"tRNS", &[0, 128]);
+        let file = test_utils::png(3, 1, 8, ColorType::Indexed, &[plte, trns], &[2, 0, 1]);
+        let bitmap = PngDecoder::read(file.as_slice())
+            .unwrap()
+            .decode_with(expand)
+            .unwrap();
+        assert_eq!(bitmap.bpp, 4);
+        assert_eq!(
+            bitmap.buffer,
+            vec![30, 31, 32, 255, 10, 11, 12, 0, 20, 21, 22, 128]
+        );
+    }
+
+    #[test]
+    fn color_keyed_transparency() {
+        let to_alpha = Transformations::new().trns_to_alpha(true);
+
+        // the key is compared at 16 bits, so only the second pixel is transparent
+        let trns = test_utils::chunk(b"tRNS", &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        let image = [1, 2, 3, 4, 5, 7, 1, 2, 3, 4, 5, 6];
+        let file = test_utils::png(2, 1, 16, ColorType::RGB, &[trns], &image);
+        let png = PngDecoder::read(file.as_slice()).unwrap();
+
+        assert_eq!(png.decode().unwrap().buffer, image);
+        let bitmap = png.decode_with(to_alpha).unwrap();
+        assert_eq!(bitmap.bpp, 8);
+        assert_eq!(
+            bitmap.buffer,
+            vec![1, 2, 3, 4, 5, 7, 0xff, 0xff, 1, 2, 3, 4, 5, 6, 0, 0]
+        );
+
+        let trns = test_utils::chunk(b"tRNS", &[0, 1]);
+        let file = test_utils::png(4, 1, 1, ColorType::Grayscale, &[trns], &[0b0110_0000]);
+        let bitmap = PngDecoder::read(file.as_slice())
+            .unwrap()
+            .decode_with(to_alpha)
+            .unwrap();
+        assert_eq!(bitmap.color_type, ColorType::GrayscaleAlpha);
+        assert_eq!((bitmap.bpp, bitmap.bit_depth, bitmap.packed), (2, 8, false));
+        assert_eq!(bitmap.buffer, vec![0, 255, 255, 0, 255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn gamma_correction() {
+        let gama = test_utils::chunk(b"gAMA", &100_000u32.to_be_bytes());
+        let srgb = test_utils::chunk(b"sRGB", &[0]);
+        let to_display = Transformations::new().gamma(2.2);
+
+        let file = test_utils::png(
+            3,
+            1,
+            8,
+            ColorType::Grayscale,
+            std::slice::from_ref(&gama),
+            &[0, 128, 255],
+        );
+        let png = PngDecoder::read(file.as_slice()).unwrap();
+        assert_eq!(png.gamma(), Some(1.0));
+        assert_eq!(
+            png.decode_with(to_display).unwrap().buffer,
+            vec![0, 186, 255]
+        );
+
+        // sRGB takes precedence, and is already encoded for a display gamma of 2.2
+        let file = test_utils::png(3, 1, 8, ColorType::Grayscale, &[gama, srgb], &[0, 128, 255]);
+        let png = PngDecoder::read(file.as_slice()).unwrap();
+        assert_eq!(png.gamma(), Some(0.45455));
+        assert_eq!(
+            png.decode_with(to_display).unwrap().buffer,
+            vec![0, 128, 255]
+        );
+
+        // 16-bit samples use a table of their own, and alpha is left alone
+        let file = test_utils::png(
+            1,
+            1,
+            16,
+            ColorType::GrayscaleAlpha,
+            &[test_utils::chunk(b"gAMA", &100_000u32.to_be_bytes())],
+            &[0x80, 0x00, 0x80, 0x00],
+        );
+        let png = PngDecoder::read(file.as_slice()).unwrap();
+        let corrected = png.decode_with(to_display).unwrap().to_u16_vec().unwrap();
+        assert_eq!(corrected, vec![47_824, 0x8000]);
+    }
+
+    #[test]
+    fn palette_index_out_of_range() {
+        let plte = test_utils::chunk(b"PLTE", &[1, 2, 3]);
+        let file = test_utils::png(2, 1, 8, ColorType::Indexed, &[plte], &[0, 1]);
+        let png = PngDecoder::read(file.as_slice()).unwrap();
+
+        assert!(png.decode().is_ok());
+        assert!(matches!(
+            png.decode_with(Transformations::new().expand_palette(true)),
+            Err(PngDecodingError::InvalidPaletteIndex {
+                index: 1,
+                palette_len: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn decode_16_bit_samples() {
+        let image = [0x12, 0x34, 0xff, 0x00, 0x00, 0x01, 0xab, 0xcd];
+        let file = test_utils::png(2, 2, 16, ColorType::Grayscale, &[], &image);
+        let png = PngDecoder::read(file.as_slice()).unwrap();
+
+        let expected = vec![0x1234, 0xff00, 0x0001, 0xabcd];
+        assert_eq!(png.decode_u16().unwrap(), expected);
+
+        let bitmap = png.decode().unwrap();
+        assert_eq!(bitmap.bit_depth, 16);
+        assert_eq!(bitmap.to_u16_vec(), Some(expected));
+        assert_eq!(
+            bitmap
+                .rows_u16()
+                .unwrap()
+                .map(|row| row.collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            vec![vec![0x1234, 0xff00], vec![0x0001, 0xabcd]]
+        );
+    }
+
+    fn with_image_data(data: &[u8]) -> Png {
+        let file = test_utils::png(2, 2, 8, ColorType::Grayscale, &[], &[0; 4]);
+        let mut png = PngDecoder::read(file.as_slice()).unwrap();
+        png.idat = test_utils::zlib(data);
+        png
+    }
+
+    #[test]
+    fn decode_invalid_image_data() {
+        assert!(matches!(
+            with_image_data(&[0, 1, 2, 5, 3, 4]).decode(),
+            Err(PngDecodingError::InvalidFilterType(5))
+        ));
+        assert!(matches!(
+            with_image_data(&[0, 1, 2, 0, 3]).decode(),
+            Err(PngDecodingError::NotEnoughImageData {
+                expected: 6,
+                found: 5
+            })
+        ));
+        assert!(matches!(
+            with_image_data(&[0, 1, 2, 0, 3, 4, 0]).decode(),
+            Err(PngDecodingError::TooMuchImageData {
+                expected: 6,
+                found: 7
+            })
+        ));
+
+        let mut png = with_image_data(&[0, 1, 2, 0, 3, 4]);
+        png.idat.truncate(png.idat.len() - 4);
+        png.idat[0] ^= 0xff;
+        assert!(matches!(png.decode(), Err(PngDecodingError::ZlibError(_))));
+
+        png.idat.clear();
+        assert!(matches!(
+            png.decode(),
+            Err(PngDecodingError::ZeroLengthIDAT)
+        ));
+    }
+}
@@ -0,0 +1,185 @@
+use std::io;
+
+use crate::{
+    chunks::IHDR,
+    common::HEADER,
+    decoder::{check_header, check_iend, DecoderOptions, DecoderState},
+    errors::{ChunkError, DecodeWarning, PngDecodingError},
+    Png,
+};
+
+/// A chunk borrowed from the bytes of a file
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct ChunkRef<'a> {
+    pub chunk_type: [u8; 4],
+    pub data: &'a [u8],
+}
+
+/// A file parsed in place from a byte slice, such as a memory-mapped file.
+///
+/// Only the IHDR chunk is interpreted. Every other chunk is kept as a slice of the
+/// input, so inspecting the metadata of a large file involves no copying. Use
+/// `to_png` to parse the remaining chunks into an owned `Png`
+#[derive(Debug, Clone)]
+pub struct PngRef<'a> {
+    pub ihdr: IHDR,
+    /// The data of each IDAT chunk, in order
+    pub idat: Vec<&'a [u8]>,
+    /// Every chunk other than IDAT and IEND, including IHDR, in the order they appear
+    pub chunks: Vec<ChunkRef<'a>>,
+    /// Problems tolerated while reading the file
+    pub warnings: Vec<DecodeWarning>,
+    options: DecoderOptions,
+}
+
+/// Split the first `len` bytes off of `data`
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], PngDecodingError> {
+    if data.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Ok(taken)
+}
+
+fn take_u32(data: &mut &[u8]) -> Result<u32, PngDecodingError> {
+    let bytes = take(data, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+impl<'a> PngRef<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, PngDecodingError> {
+        PngRef::parse_with_options(data, &DecoderOptions::default())
+    }
+
+    /// Split `data` into chunks, checking their CRCs and lengths, and parse the IHDR chunk
+    pub fn parse_with_options(
+        mut data: &'a [u8],
+        options: &DecoderOptions,
+    ) -> Result<Self, PngDecodingError> {
+        let mut state = DecoderState::new(options);
+        let mut idat = Vec::new();
+        let mut chunks = Vec::new();
+
+        state.locate_errors(|state| {
+            let mut header = [0u8; 8];
+            header.copy_from_slice(take(&mut data, HEADER.len())?);
+            check_header(header)?;
+
+            loop {
+                state.start_chunk();
+                let length = take_u32(&mut data)?;
+                let mut chunk_type = [0u8; 4];
+                chunk_type.copy_from_slice(take(&mut data, 4)?);
+                state.check_length(length, chunk_type)?;
+                let accepted = state.check_order(chunk_type)?;
+
+                let chunk_data = take(&mut data, length as usize)?;
+                let crc = take_u32(&mut data)?;
+                state.check_chunk_crc(chunk_type, chunk_data, crc)?;
+
+                match &chunk_type {
+                    _ if !accepted => {}
+                    b"IDAT" => idat.push(chunk_data),
+                    b"IEND" => {
+                        check_iend(length, crc)?;
+                        break;
+                    }
+                    b"IHDR" => {
+                        state.process_chunk(chunk_type, chunk_data)?;
+                        chunks.push(ChunkRef {
+                            chunk_type,
+                            data: chunk_data,
+                        });
+                    }
+                    _ => chunks.push(ChunkRef {
+                        chunk_type,
+                        data: chunk_data,
+                    }),
+                }
+            }
+
+            Ok(())
+        })?;
+
+        if chunks.first().map(|chunk| &chunk.chunk_type) != Some(b"IHDR") {
+            return Err(ChunkError::IHDRChunkNotFound.into());
+        }
+
+        Ok(PngRef {
+            ihdr: state.ihdr,
+            idat,
+            chunks,
+            warnings: state.warnings,
+            options: state.options,
+        })
+    }
+
+    /// The data of the first chunk of the given type
+    pub fn chunk(&self, chunk_type: &[u8; 4]) -> Option<&'a [u8]> {
+        self.chunks
+            .iter()
+            .find(|chunk| &chunk.chunk_type == chunk_type)
+            .map(|chunk| chunk.data)
+    }
+
+    /// The combined length of the data of every IDAT chunk
+    pub fn image_data_len(&self) -> usize {
+        self.idat.iter().map(|data| data.len()).sum()
+    }
+
+    /// Parse every chunk, copying them into an owned `Png`
+    pub fn to_png(&self) -> Result<Png, PngDecodingError> {
+        let mut state = DecoderState::new(&self.options);
+        state.warnings = self.warnings.clone();
+
+        for chunk in &self.chunks {
+            state.process_chunk(chunk.chunk_type, chunk.data)?;
+        }
+
+        Ok(state.into_png(self.idat.concat()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{ColorType, IEND},
+        test_utils,
+    };
+
+    #[test]
+    fn chunks_borrow_from_input() {
+        let idat = test_utils::zlib(&[0, 7, 9]);
+
+        let mut file = HEADER.to_vec();
+        file.extend(test_utils::ihdr(2, 1, 8, ColorType::Grayscale));
+        file.extend(test_utils::chunk(b"tEXt", b"Title\0borrowed"));
+        file.extend(test_utils::chunk(b"IDAT", &idat[..3]));
+        file.extend(test_utils::chunk(b"IDAT", &idat[3..]));
+        file.extend_from_slice(&IEND);
+
+        let png_ref = PngRef::parse(&file).unwrap();
+        assert_eq!(png_ref.ihdr.width, 2);
+        assert_eq!(png_ref.idat.len(), 2);
+        assert_eq!(png_ref.image_data_len(), idat.len());
+
+        let text = png_ref.chunk(b"tEXt").unwrap();
+        assert_eq!(text, b"Title\0borrowed");
+        assert!(file.as_ptr_range().contains(&text.as_ptr()));
+
+        let png = png_ref.to_png().unwrap();
+        assert_eq!(png.ancillary_chunks.tEXt[0].text, "borrowed");
+        assert_eq!(png.decode().unwrap().buffer, vec![7, 9]);
+    }
+
+    #[test]
+    fn truncated_chunk() {
+        let file = test_utils::png(1, 1, 8, ColorType::Grayscale, &[], &[0]);
+        assert!(matches!(
+            PngRef::parse(&file[..40]).map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::IoError(_))
+        ));
+    }
+}
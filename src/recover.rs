@@ -0,0 +1,360 @@
+use std::io::{self, Read};
+
+use crate::{
+    common::Bitmap,
+    decoder::{
+        check_iend, read_chunk_header, read_crc, read_header, skip_chunk_data, DecoderOptions,
+        DecoderState, PngDecoder,
+    },
+    errors::{ChunkError, DecodeWarning, PngDecodingError},
+    interlacing,
+    scanlines::ScanlineDecoder,
+    Png,
+};
+
+/// What went wrong while recovering a damaged file
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// Problems that caused data to be lost, in the order they were found
+    pub errors: Vec<PngDecodingError>,
+    /// Problems that were tolerated without losing any data
+    pub warnings: Vec<DecodeWarning>,
+    /// The number of scanlines that were decoded. The rows of each pass of an
+    /// interlaced image are counted separately
+    pub rows_decoded: usize,
+    /// The number of scanlines in the image, counted in the same way as `rows_decoded`
+    pub rows_expected: usize,
+}
+
+impl RecoveryReport {
+    /// Whether the whole file was read and every row decoded
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty() && self.rows_decoded == self.rows_expected
+    }
+}
+
+/// The result of decoding a file with `PngDecoder::recover`
+#[derive(Debug)]
+pub struct Recovered {
+    /// Everything that could be read from the file
+    pub png: Png,
+    /// The image, with any pixels that could not be decoded set to the fill value
+    pub bitmap: Bitmap,
+    pub report: RecoveryReport,
+}
+
+impl PngDecoder {
+    pub fn recover<T: Read>(f: T, fill: &[u8]) -> Result<Recovered, PngDecodingError> {
+        PngDecoder::recover_with_options(f, fill, &DecoderOptions::default())
+    }
+
+    /// Decode as much of a truncated or damaged file as possible.
+    ///
+    /// Reading stops at the first chunk that cannot be read, keeping whatever image
+    /// data came before it, and chunks that fail to parse are skipped. CRC mismatches
+    /// and out of place chunks become warnings. Rows are then decoded until the image
+    /// data runs out or is found to be corrupt.
+    ///
+    /// Pixels that could not be decoded are set by repeating `fill` along each row, so
+    /// `fill` is normally the bytes of a single pixel. An empty `fill` leaves them zeroed,
+    /// which is black, or transparent for images with an alpha channel.
+    ///
+    /// Only a missing signature or IHDR chunk, or a file that exceeds the configured
+    /// limits, is an error
+    pub fn recover_with_options<T: Read>(
+        mut f: T,
+        fill: &[u8],
+        options: &DecoderOptions,
+    ) -> Result<Recovered, PngDecodingError> {
+        let mut state = DecoderState::new(&options.relaxed());
+        let mut report = RecoveryReport::default();
+        let mut idat = Vec::new();
+
+        state.locate_errors(|state| {
+            read_header(&mut f)?;
+
+            state.start_chunk();
+            let (length, chunk_type) = read_chunk_header(&mut f)?;
+            state.check_length(length, chunk_type)?;
+            // the relaxed ordering rules would only warn, but nothing can be decoded
+            // without IHDR
+            if &chunk_type != b"IHDR" {
+                return Err(ChunkError::IHDRChunkNotFound.into());
+            }
+            state.check_order(chunk_type)?;
+            let (data, crc) = read_partial_chunk(&mut f, length).map_err(|(_, err)| err)?;
+            state.check_chunk_crc(chunk_type, &data, crc)?;
+            state.process_chunk(chunk_type, &data)
+        })?;
+
+        loop {
+            match read_chunk(&mut f, &mut state, &mut idat, &mut report) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => {
+                    report.errors.push(state.locate(err));
+                    break;
+                }
+            }
+        }
+
+        let bitmap = decode_rows(&state, &idat, fill, &mut report)?;
+
+        report.warnings = state.warnings.clone();
+
+        Ok(Recovered {
+            png: state.into_png(idat),
+            bitmap,
+            report,
+        })
+    }
+}
+
+/// Read a chunk's data and CRC, returning as much of the data as is present along with
+/// the error if the chunk is cut short
+fn read_partial_chunk<T: Read>(
+    f: &mut T,
+    length: u32,
+) -> Result<(Vec<u8>, u32), (Vec<u8>, PngDecodingError)> {
+    let mut data = Vec::new();
+    if let Err(err) = f.take(u64::from(length)).read_to_end(&mut data) {
+        return Err((data, err.into()));
+    }
+    if data.len() != length as usize {
+        return Err((data, io::Error::from(io::ErrorKind::UnexpectedEof).into()));
+    }
+
+    match read_crc(f) {
+        Ok(crc) => Ok((data, crc)),
+        Err(err) => Err((data, err)),
+    }
+}
+
+/// Read a single chunk after IHDR, keeping partial image data and skipping metadata
+/// chunks that fail to parse.
+///
+/// Returns whether there are more chunks to read
+fn read_chunk<T: Read>(
+    f: &mut T,
+    state: &mut DecoderState,
+    idat: &mut Vec<u8>,
+    report: &mut RecoveryReport,
+) -> Result<bool, PngDecodingError> {
+    state.start_chunk();
+    let (length, chunk_type) = read_chunk_header(f)?;
+    state.check_length(length, chunk_type)?;
+    let accepted = state.check_order(chunk_type)?;
+    if !accepted {
+        skip_chunk_data(f, state, length, chunk_type)?;
+        return Ok(true);
+    }
+
+    let (data, crc) = match read_partial_chunk(f, length) {
+        Ok(chunk) => chunk,
+        Err((data, err)) => {
+            if &chunk_type == b"IDAT" {
+                idat.extend_from_slice(&data);
+            }
+            return Err(err);
+        }
+    };
+    state.check_chunk_crc(chunk_type, &data, crc)?;
+
+    match &chunk_type {
+        b"IDAT" => idat.extend_from_slice(&data),
+        b"IEND" => {
+            if let Err(err) = check_iend(length, crc) {
+                report.errors.push(state.locate(err));
+            }
+            return Ok(false);
+        }
+        _ => {
+            if let Err(err) = state.process_chunk(chunk_type, &data) {
+                report.errors.push(state.locate(err));
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// Decode every row that the image data allows, filling in the rest
+fn decode_rows(
+    state: &DecoderState,
+    idat: &[u8],
+    fill: &[u8],
+    report: &mut RecoveryReport,
+) -> Result<Bitmap, PngDecodingError> {
+    let ihdr = &state.ihdr;
+    let mut scanlines = ScanlineDecoder::new(ihdr)?;
+
+    let bits_per_pixel = ihdr.bits_per_pixel();
+    let stride = interlacing::bytes_per_row(ihdr.width as usize, bits_per_pixel);
+    let interlaced = ihdr.interlace_method == 1;
+
+    let mut out = vec![0; stride * ihdr.height as usize];
+    if !fill.is_empty() {
+        for row in out.chunks_exact_mut(stride) {
+            for (byte, &fill) in row.iter_mut().zip(fill.iter().cycle()) {
+                *byte = fill;
+            }
+        }
+    }
+
+    report.rows_expected = if interlaced {
+        (0..interlacing::ADAM7_PASSES.len())
+            .map(|pass| {
+                match interlacing::pass_dimensions(pass, ihdr.width as usize, ihdr.height as usize)
+                {
+                    (0, _) | (_, 0) => 0,
+                    (_, pass_height) => pass_height,
+                }
+            })
+            .sum()
+    } else {
+        ihdr.height as usize
+    };
+
+    let mut input = idat;
+
+    while !scanlines.is_finished() {
+        let (consumed, ready) = match scanlines.decompress(input, true) {
+            Ok(progress) => progress,
+            Err(err) => {
+                report.errors.push(err);
+                break;
+            }
+        };
+        input = &input[consumed..];
+
+        if !ready {
+            continue;
+        }
+
+        let row = match scanlines.take_row() {
+            Ok(row) => row,
+            Err(err) => {
+                report.errors.push(err);
+                break;
+            }
+        };
+
+        let y = match row.pass {
+            Some(pass) => interlacing::image_y(usize::from(pass), row.y as usize),
+            None => row.y as usize,
+        };
+        let out_row = &mut out[(y * stride)..((y + 1) * stride)];
+
+        match row.pass {
+            Some(pass) => interlacing::scatter_row(
+                usize::from(pass),
+                row.data,
+                row.width as usize,
+                out_row,
+                bits_per_pixel,
+            ),
+            None => out_row.copy_from_slice(row.data),
+        }
+
+        report.rows_decoded += 1;
+    }
+
+    Ok(Bitmap {
+        width: ihdr.width,
+        height: ihdr.height,
+        color_type: ihdr.color_type,
+        bpp: std::cmp::max(1, bits_per_pixel / 8),
+        bit_depth: ihdr.bit_depth,
+        packed: ihdr.bit_depth < 8,
+        buffer: out,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{ColorType, HEADER},
+        test_utils,
+    };
+
+    #[test]
+    fn recover_truncated_image_data() {
+        let (width, height) = (4, 64);
+        // noisy rows, so that the compressed data is spread over the whole image
+        let image: Vec<u8> = (0..(width * height))
+            .map(|i: u32| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let file = test_utils::png(width, height, 8, ColorType::Grayscale, &[], &image);
+
+        // cut the file off part way through the IDAT chunk
+        let truncated = &file[..(file.len() / 2)];
+        assert!(PngDecoder::read(truncated).is_err());
+
+        let recovered = PngDecoder::recover(truncated, &[0xaa]).unwrap();
+        let report = &recovered.report;
+        assert!(!report.is_complete());
+        assert!(matches!(
+            report.errors.first().map(PngDecodingError::kind),
+            Some(PngDecodingError::IoError(_))
+        ));
+        assert_eq!(report.rows_expected, height as usize);
+        assert!(report.rows_decoded > 0 && report.rows_decoded < height as usize);
+
+        let split = report.rows_decoded * width as usize;
+        let buffer = &recovered.bitmap.buffer;
+        assert_eq!(buffer[..split], image[..split]);
+        assert!(buffer[split..].iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn recover_complete_file() {
+        let mut file = test_utils::png(2, 1, 8, ColorType::RGB, &[], &[1, 2, 3, 4, 5, 6]);
+        file.extend_from_slice(b"trailing garbage");
+
+        let recovered = PngDecoder::recover(file.as_slice(), &[]).unwrap();
+        assert!(recovered.report.is_complete());
+        assert_eq!(recovered.bitmap.buffer, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn missing_iend_and_bad_crc() {
+        let mut file = test_utils::png(1, 2, 8, ColorType::Grayscale, &[], &[7, 8]);
+        file.truncate(file.len() - 12);
+        let last = file.len() - 1;
+        file[last] ^= 1;
+
+        let recovered = PngDecoder::recover(file.as_slice(), &[]).unwrap();
+        assert_eq!(recovered.bitmap.buffer, vec![7, 8]);
+        assert_eq!(recovered.report.rows_decoded, 2);
+        assert_eq!(recovered.report.warnings.len(), 1);
+        assert!(matches!(
+            recovered.report.errors.as_slice(),
+            [err] if matches!(err.kind(), PngDecodingError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn ihdr_is_required() {
+        let idat = test_utils::chunk(b"IDAT", &test_utils::zlib(&[0, 7]));
+        let iend = test_utils::chunk(b"IEND", &[]);
+
+        // no IHDR at all, and IHDR after the image data
+        let mut no_ihdr = HEADER.to_vec();
+        no_ihdr.extend(&idat);
+        no_ihdr.extend(&iend);
+        let mut idat_first = HEADER.to_vec();
+        idat_first.extend(&idat);
+        idat_first.extend(test_utils::ihdr(1, 1, 8, ColorType::Grayscale));
+        idat_first.extend(&iend);
+
+        for file in [no_ihdr, idat_first].iter() {
+            let err = PngDecoder::recover(file.as_slice(), &[]).unwrap_err();
+            assert_eq!(err.location().and_then(|l| l.chunk_type), Some(*b"IDAT"));
+            assert!(matches!(
+                err.into_kind(),
+                PngDecodingError::ChunkError(ChunkError::IHDRChunkNotFound)
+            ));
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::io;
+
+use flate2::{Decompress, FlushDecompress, Status};
+
+use crate::{
+    chunks::IHDR,
+    errors::{MetadataError, PngDecodingError},
+    filter, interlacing,
+};
+
+/// A single unfiltered scanline
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Row<'a> {
+    /// The Adam7 pass this row belongs to, in `0..7`, or `None` if the image is not interlaced
+    pub pass: Option<u8>,
+    /// Index of this row within the image, or within the reduced image of its pass
+    pub y: u32,
+    /// Number of pixels in this row
+    pub width: u32,
+    /// The unfiltered bytes of this row. Samples narrower than 8 bits remain packed
+    pub data: &'a [u8],
+}
+
+/// Inflates and unfilters image data one scanline at a time, as compressed bytes are
+/// made available to it.
+///
+/// Only the current and previous scanlines are held in memory
+#[derive(Debug)]
+pub(crate) struct ScanlineDecoder {
+    inflater: Decompress,
+    width: usize,
+    height: usize,
+    bits_per_pixel: usize,
+    bytes_per_pixel: usize,
+    interlaced: bool,
+    /// The current Adam7 pass, always 0 for images that are not interlaced
+    pass: usize,
+    pass_width: usize,
+    pass_height: usize,
+    /// The row within the current pass
+    y: usize,
+    /// The filter type byte followed by the filtered scanline
+    raw_row: Vec<u8>,
+    filled: usize,
+    prev: Vec<u8>,
+    current: Vec<u8>,
+    /// Set once the last row of the current pass has been taken
+    pass_done: bool,
+    finished: bool,
+    /// The number of bytes of decompressed image data, including filter type bytes
+    expected_len: usize,
+}
+
+impl ScanlineDecoder {
+    pub fn new(ihdr: &IHDR) -> Result<Self, PngDecodingError> {
+        let mut decoder = ScanlineDecoder {
+            inflater: Decompress::new(true),
+            width: 0,
+            height: 0,
+            bits_per_pixel: 0,
+            bytes_per_pixel: 0,
+            interlaced: false,
+            pass: 0,
+            pass_width: 0,
+            pass_height: 0,
+            y: 0,
+            raw_row: Vec::new(),
+            filled: 0,
+            prev: Vec::new(),
+            current: Vec::new(),
+            pass_done: false,
+            finished: false,
+            expected_len: 0,
+        };
+        decoder.reset(ihdr)?;
+        Ok(decoder)
+    }
+
+    /// Prepare to decode a new image, reusing existing allocations
+    pub fn reset(&mut self, ihdr: &IHDR) -> Result<(), PngDecodingError> {
+        if ihdr.width == 0 {
+            return Err(MetadataError::InvalidWidth { width: 0 }.into());
+        }
+
+        if ihdr.height == 0 {
+            return Err(MetadataError::InvalidHeight { height: 0 }.into());
+        }
+
+        self.inflater.reset(true);
+        self.width = ihdr.width as usize;
+        self.height = ihdr.height as usize;
+        self.bits_per_pixel = ihdr.bits_per_pixel();
+        self.bytes_per_pixel = std::cmp::max(1, self.bits_per_pixel / 8);
+        self.interlaced = ihdr.interlace_method == 1;
+        self.finished = false;
+        self.expected_len = ihdr.image_data_len();
+        self.start_pass(0);
+
+        Ok(())
+    }
+
+    /// Whether every scanline of the image has been decoded
+    pub const fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The index and dimensions of the first non-empty pass at or after `pass`
+    fn find_pass(&self, pass: usize) -> Option<(usize, usize, usize)> {
+        if !self.interlaced {
+            return if pass == 0 {
+                Some((0, self.width, self.height))
+            } else {
+                None
+            };
+        }
+
+        (pass..7).find_map(|pass| {
+            match interlacing::pass_dimensions(pass, self.width, self.height) {
+                (0, _) | (_, 0) => None,
+                (pass_width, pass_height) => Some((pass, pass_width, pass_height)),
+            }
+        })
+    }
+
+    /// Begin decoding at the first non-empty pass at or after `pass`
+    fn start_pass(&mut self, pass: usize) {
+        let (pass, pass_width, pass_height) = match self.find_pass(pass) {
+            Some(found) => found,
+            None => {
+                self.finished = true;
+                return;
+            }
+        };
+
+        let stride = interlacing::bytes_per_row(pass_width, self.bits_per_pixel);
+
+        self.pass = pass;
+        self.pass_width = pass_width;
+        self.pass_height = pass_height;
+        self.y = 0;
+        self.filled = 0;
+        self.pass_done = false;
+
+        self.raw_row.clear();
+        self.raw_row.resize(stride + 1, 0);
+        self.prev.clear();
+        self.prev.resize(stride, 0);
+        self.current.clear();
+        self.current.resize(stride, 0);
+    }
+
+    /// Inflate bytes from `input` until the current scanline is complete.
+    ///
+    /// Returns the number of bytes of `input` that were consumed, and whether a scanline
+    /// is ready to be taken with `take_row`. `end_of_input` signals that no more image
+    /// data exists beyond `input`
+    pub fn decompress(
+        &mut self,
+        input: &[u8],
+        end_of_input: bool,
+    ) -> Result<(usize, bool), PngDecodingError> {
+        if self.finished {
+            return Ok((0, false));
+        }
+
+        if self.pass_done {
+            self.start_pass(self.pass + 1);
+        }
+
+        let flush = if end_of_input {
+            FlushDecompress::Finish
+        } else {
+            FlushDecompress::None
+        };
+
+        let total_in = self.inflater.total_in();
+        let total_out = self.inflater.total_out();
+
+        let status = self
+            .inflater
+            .decompress(input, &mut self.raw_row[self.filled..], flush)
+            .map_err(|err| PngDecodingError::ZlibError(io::Error::from(err)))?;
+
+        let consumed = (self.inflater.total_in() - total_in) as usize;
+        let produced = (self.inflater.total_out() - total_out) as usize;
+        self.filled += produced;
+
+        if self.filled == self.raw_row.len() {
+            return Ok((consumed, true));
+        }
+
+        if status == Status::StreamEnd || (end_of_input && produced == 0) {
+            return Err(PngDecodingError::NotEnoughImageData {
+                expected: self.expected_len,
+                found: self.inflater.total_out() as usize,
+            });
+        }
+
+        Ok((consumed, false))
+    }
+
+    /// Check that `input`, the image data remaining after the last scanline, contains
+    /// only the end of the zlib stream
+    pub fn check_end(&mut self, input: &[u8]) -> Result<(), PngDecodingError> {
+        let total_out = self.inflater.total_out();
+
+        self.inflater
+            .decompress(input, &mut [0], FlushDecompress::Finish)
+            .map_err(|err| PngDecodingError::ZlibError(io::Error::from(err)))?;
+
+        if self.inflater.total_out() > total_out {
+            return Err(PngDecodingError::TooMuchImageData {
+                expected: self.expected_len,
+                found: self.inflater.total_out() as usize,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Unfilter the completed scanline and advance to the next one
+    pub fn take_row(&mut self) -> Result<Row<'_>, PngDecodingError> {
+        debug_assert_eq!(self.filled, self.raw_row.len());
+
+        filter::unfilter_row(
+            self.raw_row[0],
+            &self.prev,
+            &self.raw_row[1..],
+            &mut self.current,
+            self.bytes_per_pixel,
+        )?;
+        std::mem::swap(&mut self.prev, &mut self.current);
+
+        let y = self.y;
+        self.y += 1;
+        self.filled = 0;
+
+        // the next pass is only started once more data is needed, so that the
+        // row being returned is not cleared
+        if self.y == self.pass_height {
+            self.pass_done = true;
+            self.finished = self.find_pass(self.pass + 1).is_none();
+        }
+
+        Ok(Row {
+            pass: if self.interlaced {
+                Some(self.pass as u8)
+            } else {
+                None
+            },
+            y: y as u32,
+            width: self.pass_width as u32,
+            data: &self.prev,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{common::ColorType, test_utils};
+
+    #[test]
+    fn interlaced_rows_are_yielded_by_pass() {
+        let (width, height) = (5, 3);
+        let image: Vec<u8> = (0..(width * height)).map(|i| i as u8).collect();
+        let compressed = test_utils::zlib(&test_utils::encode_adam7(&image, width, height, 8));
+
+        let ihdr = IHDR::new(
+            width as u32,
+            height as u32,
+            8,
+            ColorType::Grayscale,
+            0,
+            0,
+            1,
+        )
+        .unwrap();
+        let mut decoder = ScanlineDecoder::new(&ihdr).unwrap();
+
+        let mut rows = Vec::new();
+        let mut input: &[u8] = &compressed;
+        while !decoder.is_finished() {
+            // feed a single byte at a time
+            let (consumed, ready) = decoder.decompress(&input[..1], false).unwrap();
+            input = &input[consumed..];
+            if ready {
+                let row = decoder.take_row().unwrap();
+                rows.push((row.pass.unwrap(), row.y, row.data.to_vec()));
+            }
+        }
+
+        // pass 2 starts at row 4, so is empty for a 3 row image
+        assert_eq!(
+            rows,
+            vec![
+                (0, 0, vec![0]),
+                (1, 0, vec![4]),
+                (3, 0, vec![2]),
+                (4, 0, vec![10, 12, 14]),
+                (5, 0, vec![1, 3]),
+                (5, 1, vec![11, 13]),
+                (6, 0, vec![5, 6, 7, 8, 9]),
+            ]
+        );
+    }
+}
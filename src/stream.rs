@@ -0,0 +1,366 @@
+use std::io::{self, BufRead, Read};
+
+use crc32fast::Hasher;
+
+use crate::{
+    chunks::{AncillaryChunks, IHDR, PLTE},
+    decoder::{
+        check_iend, read_chunk_data, read_chunk_header, read_crc, read_header, skip_chunk_data,
+        DecoderOptions, DecoderState, HashWriter,
+    },
+    errors::{DecodeWarning, PngDecodingError},
+    scanlines::{Row, ScanlineDecoder},
+    Png,
+};
+
+/// Decodes an image one row at a time, without holding the whole image in memory.
+///
+/// Chunks are read from the underlying reader only as they are needed. Image data is
+/// inflated incrementally straight out of the reader's buffer, and only the current
+/// and previous rows are kept, so images larger than the available memory can be
+/// processed.
+///
+/// Rows of interlaced images are yielded pass by pass, as they appear in the file
+#[derive(Debug)]
+pub struct StreamingDecoder<R> {
+    reader: R,
+    state: DecoderState,
+    scanlines: ScanlineDecoder,
+    /// Bytes of the current IDAT chunk that have not yet been read
+    idat_remaining: u32,
+    /// CRC of the current IDAT chunk
+    hasher: Hasher,
+    /// The header of the chunk that followed the last IDAT chunk, once it has been read
+    next_chunk: Option<(u32, [u8; 4])>,
+}
+
+impl<R: BufRead> StreamingDecoder<R> {
+    pub fn new(reader: R) -> Result<Self, PngDecodingError> {
+        StreamingDecoder::with_options(reader, &DecoderOptions::default())
+    }
+
+    /// Read every chunk up to the start of the image data
+    pub fn with_options(mut reader: R, options: &DecoderOptions) -> Result<Self, PngDecodingError> {
+        let mut state = DecoderState::new(options);
+
+        let (idat_length, scanlines) = state.locate_errors(|state| {
+            read_header(&mut reader)?;
+
+            let idat_length = loop {
+                state.start_chunk();
+                let (length, chunk_type) = read_chunk_header(&mut reader)?;
+                state.check_length(length, chunk_type)?;
+
+                if &chunk_type == b"IEND" {
+                    return Err(PngDecodingError::ZeroLengthIDAT);
+                }
+
+                let accepted = state.check_order(chunk_type)?;
+                if &chunk_type == b"IDAT" {
+                    break length;
+                }
+
+                if accepted {
+                    let (data, _) = read_chunk_data(&mut reader, state, length, chunk_type)?;
+                    state.process_chunk(chunk_type, &data)?;
+                } else {
+                    skip_chunk_data(&mut reader, state, length, chunk_type)?;
+                }
+            };
+
+            Ok((idat_length, ScanlineDecoder::new(&state.ihdr)?))
+        })?;
+
+        let mut hasher = Hasher::new();
+        hasher.update(b"IDAT");
+
+        Ok(StreamingDecoder {
+            reader,
+            state,
+            scanlines,
+            idat_remaining: idat_length,
+            hasher,
+            next_chunk: None,
+        })
+    }
+
+    pub fn ihdr(&self) -> &IHDR {
+        &self.state.ihdr
+    }
+
+    pub fn palette(&self) -> Option<&PLTE> {
+        self.state.plte.as_ref()
+    }
+
+    /// Ancillary chunks that appear before the image data
+    pub fn ancillary_chunks(&self) -> &AncillaryChunks {
+        &self.state.ancillary_chunks
+    }
+
+    pub fn warnings(&self) -> &[DecodeWarning] {
+        &self.state.warnings
+    }
+
+    /// Decode the next row, or return `None` once every row has been decoded
+    pub fn next_row(&mut self) -> Result<Option<Row<'_>>, PngDecodingError> {
+        match self.fill_row() {
+            Ok(true) => {}
+            Ok(false) => return Ok(None),
+            Err(err) => return Err(self.state.locate(err)),
+        }
+
+        let state = &self.state;
+        self.scanlines
+            .take_row()
+            .map(Some)
+            .map_err(|err| state.locate(err))
+    }
+
+    /// Inflate image data until the next row is ready to be taken.
+    ///
+    /// Returns false once every row has been decoded
+    fn fill_row(&mut self) -> Result<bool, PngDecodingError> {
+        loop {
+            if self.scanlines.is_finished() {
+                self.check_image_end()?;
+                return Ok(false);
+            }
+
+            if self.idat_remaining == 0 && self.next_chunk.is_none() {
+                self.next_idat()?;
+                continue;
+            }
+
+            let input = if self.idat_remaining > 0 {
+                let buffer = self.reader.fill_buf()?;
+                if buffer.is_empty() {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                let len = std::cmp::min(buffer.len(), self.idat_remaining as usize);
+                &buffer[..len]
+            } else {
+                &[]
+            };
+
+            let (consumed, ready) = self
+                .scanlines
+                .decompress(input, self.next_chunk.is_some())?;
+
+            if self.state.checks_crc() {
+                self.hasher.update(&input[..consumed]);
+            }
+            self.reader.consume(consumed);
+            self.idat_remaining -= consumed as u32;
+
+            if ready {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Check that the image data remaining after the last row holds only the end of the
+    /// zlib stream, reading up to the first chunk after the image data
+    fn check_image_end(&mut self) -> Result<(), PngDecodingError> {
+        while self.next_chunk.is_none() {
+            if self.idat_remaining == 0 {
+                self.next_idat()?;
+                continue;
+            }
+
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let len = std::cmp::min(buffer.len(), self.idat_remaining as usize);
+            self.scanlines.check_end(&buffer[..len])?;
+
+            if self.state.checks_crc() {
+                self.hasher.update(&buffer[..len]);
+            }
+            self.reader.consume(len);
+            self.idat_remaining -= len as u32;
+        }
+
+        Ok(())
+    }
+
+    /// Finish the current IDAT chunk, and read the header of the next chunk.
+    ///
+    /// If the next chunk is not an IDAT chunk, it is the first chunk after the image data
+    fn next_idat(&mut self) -> Result<(), PngDecodingError> {
+        let crc = read_crc(&mut self.reader)?;
+        let hasher = std::mem::replace(&mut self.hasher, Hasher::new());
+        self.state.check_crc(*b"IDAT", hasher.finalize(), crc)?;
+
+        self.state.start_chunk();
+        let (length, chunk_type) = read_chunk_header(&mut self.reader)?;
+        self.state.check_length(length, chunk_type)?;
+
+        if &chunk_type == b"IDAT" {
+            self.state.check_order(chunk_type)?;
+            self.idat_remaining = length;
+            self.hasher.update(b"IDAT");
+        } else {
+            self.next_chunk = Some((length, chunk_type));
+        }
+
+        Ok(())
+    }
+
+    /// Skip any remaining image data, and read the chunks that follow it. If every row
+    /// has been decoded, the rest of the image data is checked instead of skipped.
+    ///
+    /// The returned `Png` has all of the file's metadata, but no image data
+    pub fn finish(mut self) -> Result<Png, PngDecodingError> {
+        match self.read_remaining_chunks() {
+            Ok(()) => Ok(self.state.into_png(Vec::new())),
+            Err(err) => Err(self.state.locate(err)),
+        }
+    }
+
+    fn read_remaining_chunks(&mut self) -> Result<(), PngDecodingError> {
+        if self.scanlines.is_finished() {
+            self.check_image_end()?;
+        }
+
+        while self.next_chunk.is_none() {
+            let remaining = u64::from(self.idat_remaining);
+            let skipped = if self.state.checks_crc() {
+                io::copy(
+                    &mut (&mut self.reader).take(remaining),
+                    &mut HashWriter(&mut self.hasher),
+                )?
+            } else {
+                io::copy(&mut (&mut self.reader).take(remaining), &mut io::sink())?
+            };
+            if skipped != remaining {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.idat_remaining = 0;
+            self.next_idat()?;
+        }
+
+        let (mut length, mut chunk_type) = self.next_chunk.take().unwrap();
+
+        loop {
+            let accepted = self.state.check_order(chunk_type)?;
+
+            // the image data has already been decoded, so later IDAT chunks can only
+            // be skipped
+            if !accepted || &chunk_type == b"IDAT" {
+                skip_chunk_data(&mut self.reader, &mut self.state, length, chunk_type)?;
+            } else {
+                let (data, crc) =
+                    read_chunk_data(&mut self.reader, &mut self.state, length, chunk_type)?;
+
+                if &chunk_type == b"IEND" {
+                    check_iend(length, crc)?;
+                    break;
+                }
+                self.state.process_chunk(chunk_type, &data)?;
+            }
+
+            self.state.start_chunk();
+            let (next_length, next_chunk_type) = read_chunk_header(&mut self.reader)?;
+            self.state.check_length(next_length, next_chunk_type)?;
+            length = next_length;
+            chunk_type = next_chunk_type;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{ColorType, HEADER, IEND},
+        test_utils,
+    };
+
+    #[test]
+    fn stream_rows_across_idat_chunks() {
+        let (width, height) = (7, 9);
+        let image: Vec<u8> = (0..(width * height * 3)).map(|i| (i * 7) as u8).collect();
+        let compressed = test_utils::zlib(&test_utils::unfiltered_scanlines(&image, width * 3));
+
+        let mut file = HEADER.to_vec();
+        file.extend(test_utils::ihdr(
+            width as u32,
+            height as u32,
+            8,
+            ColorType::RGB,
+        ));
+        for piece in compressed.chunks(5) {
+            file.extend(test_utils::chunk(b"IDAT", piece));
+        }
+        file.extend(test_utils::chunk(b"tEXt", b"Comment\0after the image"));
+        file.extend_from_slice(&IEND);
+
+        // a tiny buffer forces the inflater to be fed a few bytes at a time
+        let reader = io::BufReader::with_capacity(3, file.as_slice());
+        let mut decoder = StreamingDecoder::new(reader).unwrap();
+        assert_eq!(decoder.ihdr().width, width as u32);
+
+        let mut rows = Vec::new();
+        while let Some(row) = decoder.next_row().unwrap() {
+            assert_eq!(row.pass, None);
+            assert_eq!(row.y as usize, rows.len());
+            rows.push(row.data.to_vec());
+        }
+        assert_eq!(rows.concat(), image);
+
+        let png = decoder.finish().unwrap();
+        assert!(png.idat.is_empty());
+        assert_eq!(png.ancillary_chunks.tEXt[0].keyword, "Comment");
+    }
+
+    #[test]
+    fn finish_skips_remaining_rows() {
+        let file = test_utils::png(2, 2, 8, ColorType::Grayscale, &[], &[1, 2, 3, 4]);
+        let mut decoder = StreamingDecoder::new(file.as_slice()).unwrap();
+        assert_eq!(decoder.next_row().unwrap().unwrap().data, &[1, 2]);
+        assert!(decoder.finish().is_ok());
+    }
+
+    #[test]
+    fn excess_image_data() {
+        // a 1x1 image needs 2 bytes of image data, including the filter type byte
+        let mut file = HEADER.to_vec();
+        file.extend(test_utils::ihdr(1, 1, 8, ColorType::Grayscale));
+        file.extend(test_utils::chunk(b"IDAT", &test_utils::zlib(&[0, 1, 2, 3])));
+        file.extend_from_slice(&IEND);
+
+        let mut decoder = StreamingDecoder::new(file.as_slice()).unwrap();
+        assert_eq!(decoder.next_row().unwrap().unwrap().data, &[1]);
+        assert!(matches!(
+            decoder.next_row().map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::TooMuchImageData { .. })
+        ));
+
+        let mut decoder = StreamingDecoder::new(file.as_slice()).unwrap();
+        decoder.next_row().unwrap();
+        assert!(matches!(
+            decoder.finish().map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::TooMuchImageData { .. })
+        ));
+    }
+
+    #[test]
+    fn truncated_image_data() {
+        let mut file = test_utils::png(2, 2, 8, ColorType::Grayscale, &[], &[1, 2, 3, 4]);
+        // remove IEND, the IDAT CRC, and the end of the zlib stream
+        file.truncate(file.len() - 24);
+
+        let mut decoder = StreamingDecoder::new(file.as_slice()).unwrap();
+        let result = (|| {
+            while decoder.next_row()?.is_some() {}
+            Ok(())
+        })();
+        assert!(matches!(
+            result.map_err(PngDecodingError::into_kind),
+            Err(PngDecodingError::IoError(_))
+        ));
+    }
+}
@@ -0,0 +1,116 @@
+//! Helpers for building PNG files in memory
+
+use std::io::Read;
+
+use flate2::{bufread::ZlibEncoder, Compression};
+
+use crate::{
+    common::{ColorType, HEADER, IEND},
+    interlacing,
+};
+
+/// Serialize a chunk with its length and CRC
+pub(crate) fn chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(12 + data.len());
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+    out.extend_from_slice(&hasher.finalize().to_be_bytes());
+
+    out
+}
+
+pub(crate) fn ihdr(width: u32, height: u32, bit_depth: u8, color_type: ColorType) -> Vec<u8> {
+    let mut data = Vec::with_capacity(13);
+    data.extend_from_slice(&width.to_be_bytes());
+    data.extend_from_slice(&height.to_be_bytes());
+    data.extend_from_slice(&[bit_depth, color_type as u8, 0, 0, 0]);
+    chunk(b"IHDR", &data)
+}
+
+pub(crate) fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    ZlibEncoder::new(data, Compression::fast())
+        .read_to_end(&mut out)
+        .unwrap();
+    out
+}
+
+/// Prefix each of the unfiltered scanlines in `image` with filter type 0
+pub(crate) fn unfiltered_scanlines(image: &[u8], bytes_per_row: usize) -> Vec<u8> {
+    image
+        .chunks_exact(bytes_per_row)
+        .flat_map(|row| std::iter::once(0).chain(row.iter().copied()))
+        .collect()
+}
+
+/// A complete, non-interlaced PNG file with `chunks` between IHDR and IDAT
+pub(crate) fn png(
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: ColorType,
+    chunks: &[Vec<u8>],
+    image: &[u8],
+) -> Vec<u8> {
+    let bits_per_row = width as usize * usize::from(bit_depth) * usize::from(color_type.channels());
+    let bytes_per_row = bits_per_row.div_ceil(8);
+
+    let mut out = HEADER.to_vec();
+    out.extend(ihdr(width, height, bit_depth, color_type));
+    for c in chunks {
+        out.extend_from_slice(c);
+    }
+    out.extend(chunk(
+        b"IDAT",
+        &zlib(&unfiltered_scanlines(image, bytes_per_row)),
+    ));
+    out.extend_from_slice(&IEND);
+    out
+}
+
+/// Interlace an unfiltered image, using filter type 0 for every scanline
+pub(crate) fn encode_adam7(
+    image: &[u8],
+    width: usize,
+    height: usize,
+    bits_per_pixel: usize,
+) -> Vec<u8> {
+    let stride = interlacing::bytes_per_row(width, bits_per_pixel);
+    let mut out = Vec::new();
+
+    for (pass, &(x_start, y_start, x_step, y_step)) in interlacing::ADAM7_PASSES.iter().enumerate()
+    {
+        let (pass_width, pass_height) = interlacing::pass_dimensions(pass, width, height);
+        if pass_width == 0 || pass_height == 0 {
+            continue;
+        }
+
+        let pass_stride = interlacing::bytes_per_row(pass_width, bits_per_pixel);
+
+        for pass_y in 0..pass_height {
+            let y = y_start + pass_y * y_step;
+            let row = &image[(y * stride)..((y + 1) * stride)];
+            let mut pass_row = vec![0; pass_stride];
+
+            for pass_x in 0..pass_width {
+                interlacing::copy_pixel(
+                    row,
+                    x_start + pass_x * x_step,
+                    &mut pass_row,
+                    pass_x,
+                    bits_per_pixel,
+                );
+            }
+
+            out.push(0);
+            out.extend(pass_row);
+        }
+    }
+
+    out
+}
@@ -0,0 +1,509 @@
+use crate::{
+    chunks::{tRNS, IHDR, PLTE},
+    common::{Bitmap, ColorType},
+    errors::{ChunkError, PngDecodingError},
+};
+
+/// Transformations applied to pixel data as it is decoded, so that the output has a
+/// predictable format whatever the file contains.
+///
+/// Palette expansion and tRNS conversion happen first, followed by the changes to the
+/// color channels and finally the bit depth. Samples narrower than 8 bits are scaled
+/// up to 8 bits whenever the output is not grayscale, since no other color type can
+/// hold them. Indexed images are left as palette indices unless `expand_palette` is
+/// set. For example, every image can be decoded as 8-bit RGBA with
+///
+/// ```
+/// # use rpng::Transformations;
+/// let rgba8 = Transformations::new()
+///     .expand_palette(true)
+///     .trns_to_alpha(true)
+///     .gray_to_rgb(true)
+///     .add_alpha(true)
+///     .strip_16(true);
+/// ```
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
+pub struct Transformations {
+    pub(crate) unpack: bool,
+    pub(crate) expand_palette: bool,
+    pub(crate) trns_to_alpha: bool,
+    pub(crate) gray_to_rgb: bool,
+    pub(crate) add_alpha: bool,
+    pub(crate) strip_alpha: bool,
+    pub(crate) strip_16: bool,
+    pub(crate) expand_16: bool,
+    /// The display gamma multiplied by 100000, in the same way as `gAMA`
+    pub(crate) display_gamma: Option<u32>,
+}
+
+impl Transformations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expand samples narrower than 8 bits so that each occupies its own byte.
+    ///
+    /// Sample values are not scaled, so a 2-bit sample will remain in `0..=3`
+    pub fn unpack(mut self, unpack: bool) -> Self {
+        self.unpack = unpack;
+        self
+    }
+
+    /// Replace the palette indices of indexed images with the colors they refer to.
+    ///
+    /// The output is 8-bit RGB, or 8-bit RGBA if the image has a tRNS chunk. Palette
+    /// entries without a tRNS entry are opaque
+    pub fn expand_palette(mut self, expand_palette: bool) -> Self {
+        self.expand_palette = expand_palette;
+        self
+    }
+
+    /// Add an alpha channel to grayscale and RGB images with a tRNS chunk, making
+    /// pixels of the transparent color fully transparent and every other pixel opaque.
+    ///
+    /// The transparent color is compared with the samples at the image's own bit depth
+    pub fn trns_to_alpha(mut self, trns_to_alpha: bool) -> Self {
+        self.trns_to_alpha = trns_to_alpha;
+        self
+    }
+
+    /// Copy the gray sample of grayscale images into each of the red, green and blue
+    /// channels
+    pub fn gray_to_rgb(mut self, gray_to_rgb: bool) -> Self {
+        self.gray_to_rgb = gray_to_rgb;
+        self
+    }
+
+    /// Add a fully opaque alpha channel to images without one
+    pub fn add_alpha(mut self, add_alpha: bool) -> Self {
+        self.add_alpha = add_alpha;
+        self
+    }
+
+    /// Remove the alpha channel, including one that would be added by another
+    /// transformation
+    pub fn strip_alpha(mut self, strip_alpha: bool) -> Self {
+        self.strip_alpha = strip_alpha;
+        self
+    }
+
+    /// Reduce 16-bit samples to 8 bits, rounding to the nearest value
+    pub fn strip_16(mut self, strip_16: bool) -> Self {
+        self.strip_16 = strip_16;
+        self
+    }
+
+    /// Widen samples of 8 bits or fewer to 16 bits
+    pub fn expand_16(mut self, expand_16: bool) -> Self {
+        self.expand_16 = expand_16;
+        self
+    }
+
+    /// Correct samples from the image's gamma to that of a display, such as 2.2 for a
+    /// typical monitor.
+    ///
+    /// The image's gamma is read from its sRGB chunk, or its gAMA chunk if there is no
+    /// sRGB chunk, and images with neither are left unchanged. Alpha samples are never
+    /// corrected, and samples narrower than 8 bits are scaled up to 8 bits.
+    ///
+    /// A display gamma that is not a positive, finite number turns gamma correction off
+    pub fn gamma(mut self, display_gamma: f64) -> Self {
+        // stored like a gAMA value, so anything that rounds to 0 is unusable too
+        let scaled = (display_gamma * 100_000.0).round();
+        self.display_gamma = if scaled.is_finite() && scaled >= 1.0 {
+            Some(scaled as u32)
+        } else {
+            None
+        };
+        self
+    }
+}
+
+/// Expand packed scanlines of sub-byte samples to one sample per byte
+pub(crate) fn unpack(buffer: &[u8], width: usize, height: usize, bit_depth: u8) -> Vec<u8> {
+    let bit_depth = usize::from(bit_depth);
+    let stride = (width * bit_depth).div_ceil(8);
+    let mask = (1u8 << bit_depth) - 1;
+
+    let mut out = Vec::with_capacity(width * height);
+
+    for row in buffer.chunks_exact(stride).take(height) {
+        for x in 0..width {
+            let bit = x * bit_depth;
+            let shift = 8 - bit_depth - (bit % 8);
+            out.push((row[bit / 8] >> shift) & mask);
+        }
+    }
+
+    out
+}
+
+/// The color type and bit depth of the output of `transformations`, for an image of
+/// the given color type and bit depth
+fn output_format(
+    transformations: Transformations,
+    color_type: ColorType,
+    bit_depth: u8,
+    trns: Option<&tRNS>,
+    gamma_corrected: bool,
+) -> (ColorType, u8) {
+    if color_type == ColorType::Indexed && !transformations.expand_palette {
+        return (color_type, bit_depth);
+    }
+
+    let (mut color, mut alpha, mut bit_depth) = match color_type {
+        ColorType::Indexed => (true, matches!(trns, Some(tRNS::Indexed { .. })), 8),
+        ColorType::Grayscale => (false, false, bit_depth),
+        ColorType::GrayscaleAlpha => (false, true, bit_depth),
+        ColorType::RGB => (true, false, bit_depth),
+        ColorType::RGBA => (true, true, bit_depth),
+    };
+
+    if transformations.trns_to_alpha
+        && matches!(trns, Some(tRNS::Grayscale { .. } | tRNS::RGB { .. }))
+    {
+        alpha = true;
+    }
+    alpha = (alpha || transformations.add_alpha) && !transformations.strip_alpha;
+    color |= transformations.gray_to_rgb;
+
+    let color_type = match (color, alpha) {
+        (false, false) => ColorType::Grayscale,
+        (false, true) => ColorType::GrayscaleAlpha,
+        (true, false) => ColorType::RGB,
+        (true, true) => ColorType::RGBA,
+    };
+
+    if bit_depth == 16 && transformations.strip_16 {
+        bit_depth = 8;
+    }
+    // only grayscale may have samples narrower than 8 bits
+    if bit_depth < 8
+        && (color_type != ColorType::Grayscale || transformations.expand_16 || gamma_corrected)
+    {
+        bit_depth = 8;
+    }
+    if bit_depth == 8 && transformations.expand_16 {
+        bit_depth = 16;
+    }
+
+    (color_type, bit_depth)
+}
+
+/// Apply `transformations` to unfiltered image data, laid out in the same way as the
+/// buffer returned by `Png::decode`
+pub(crate) fn apply(
+    transformations: Transformations,
+    buffer: Vec<u8>,
+    ihdr: &IHDR,
+    plte: Option<&PLTE>,
+    trns: Option<&tRNS>,
+    gamma: Option<f64>,
+) -> Result<Bitmap, PngDecodingError> {
+    let width = ihdr.width as usize;
+    let height = ihdr.height as usize;
+
+    // palette indices are never corrected
+    let exponent = match (gamma, transformations.display_gamma) {
+        _ if ihdr.color_type == ColorType::Indexed && !transformations.expand_palette => None,
+        (Some(gamma), Some(display_gamma)) => Some(100_000.0 / (gamma * f64::from(display_gamma))),
+        _ => None,
+    };
+
+    let (color_type, bit_depth) = output_format(
+        transformations,
+        ihdr.color_type,
+        ihdr.bit_depth,
+        trns,
+        exponent.is_some(),
+    );
+    let gamma_table = exponent.map(|exponent| gamma_table(exponent, bit_depth));
+
+    if (color_type, bit_depth) == (ihdr.color_type, ihdr.bit_depth) && gamma_table.is_none() {
+        let unpack_samples = bit_depth < 8 && transformations.unpack;
+        let buffer = if unpack_samples {
+            unpack(&buffer, width, height, bit_depth)
+        } else {
+            buffer
+        };
+        let packed = bit_depth < 8 && !unpack_samples;
+        return Ok(bitmap(ihdr, color_type, bit_depth, packed, buffer));
+    }
+
+    let samples: Vec<u16> = match ihdr.bit_depth {
+        16 => buffer
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect(),
+        8 => buffer.iter().map(|&sample| u16::from(sample)).collect(),
+        _ => unpack(&buffer, width, height, ihdr.bit_depth)
+            .iter()
+            .map(|&sample| u16::from(sample))
+            .collect(),
+    };
+
+    let key = match trns {
+        _ if !transformations.trns_to_alpha => None,
+        Some(tRNS::Grayscale { grayscale }) => Some(vec![*grayscale]),
+        Some(tRNS::RGB { red, green, blue }) => Some(vec![*red, *green, *blue]),
+        _ => None,
+    };
+
+    let plte = match ihdr.color_type {
+        ColorType::Indexed => Some(plte.ok_or(ChunkError::PLTEChunkNotFound)?),
+        _ => None,
+    };
+    let palette_alpha = match trns {
+        Some(tRNS::Indexed { entries }) => entries.as_slice(),
+        _ => &[],
+    };
+
+    let in_channels = usize::from(ihdr.color_type.channels());
+    let in_color_channels = match ihdr.color_type {
+        ColorType::RGB | ColorType::RGBA => 3,
+        _ => 1,
+    };
+    let out_color = matches!(color_type, ColorType::RGB | ColorType::RGBA);
+    let out_alpha = matches!(color_type, ColorType::GrayscaleAlpha | ColorType::RGBA);
+
+    let bytes_per_sample = if bit_depth == 16 { 2 } else { 1 };
+    let mut out =
+        Vec::with_capacity(width * height * usize::from(color_type.channels()) * bytes_per_sample);
+    let mut push = |sample: u16, from: u8, correct: bool| {
+        let mut sample = rescale(sample, from, bit_depth);
+        if let (true, Some(table)) = (correct, &gamma_table) {
+            sample = table[usize::from(sample)];
+        }
+        if bit_depth == 16 {
+            out.extend_from_slice(&sample.to_be_bytes());
+        } else {
+            out.push(sample as u8);
+        }
+    };
+
+    for pixel in samples.chunks_exact(in_channels) {
+        // palette entries are always 8-bit, whatever the bit depth of the indices
+        let (color, alpha, depth): ([u16; 3], Option<u16>, u8) = match plte {
+            Some(plte) => {
+                let index = pixel[0] as u8;
+                let entry = plte.entries.get(usize::from(index)).ok_or(
+                    PngDecodingError::InvalidPaletteIndex {
+                        index,
+                        palette_len: plte.entries.len(),
+                    },
+                )?;
+                let alpha = palette_alpha.get(usize::from(index)).copied();
+                (entry.to_array(), Some(alpha.map_or(255, u16::from)), 8)
+            }
+            None => {
+                let (color, alpha) = pixel.split_at(in_color_channels);
+                let alpha = match &key {
+                    Some(key) if key.as_slice() == color => Some(0),
+                    _ => alpha.first().copied(),
+                };
+                match *color {
+                    [gray] => ([gray; 3], alpha, ihdr.bit_depth),
+                    [red, green, blue] => ([red, green, blue], alpha, ihdr.bit_depth),
+                    _ => unreachable!(),
+                }
+            }
+        };
+
+        if out_color {
+            color.iter().for_each(|&sample| push(sample, depth, true));
+        } else {
+            push(color[0], depth, true);
+        }
+        if out_alpha {
+            push(alpha.unwrap_or(((1u32 << depth) - 1) as u16), depth, false);
+        }
+    }
+
+    Ok(bitmap(ihdr, color_type, bit_depth, false, out))
+}
+
+fn bitmap(
+    ihdr: &IHDR,
+    color_type: ColorType,
+    bit_depth: u8,
+    packed: bool,
+    buffer: Vec<u8>,
+) -> Bitmap {
+    Bitmap {
+        width: ihdr.width,
+        height: ihdr.height,
+        color_type,
+        bpp: usize::from(color_type.channels()) * std::cmp::max(1, usize::from(bit_depth) / 8),
+        bit_depth,
+        packed,
+        buffer,
+    }
+}
+
+/// A lookup table raising every sample of the given bit depth to the power of
+/// `exponent`, as a fraction of the largest sample
+fn gamma_table(exponent: f64, bit_depth: u8) -> Vec<u16> {
+    let max = f64::from((1u32 << bit_depth) - 1);
+    (0..(1u32 << bit_depth))
+        .map(|sample| ((f64::from(sample) / max).powf(exponent) * max).round() as u16)
+        .collect()
+}
+
+/// Scale a sample from one bit depth to another, so that the largest value of one is
+/// the largest value of the other
+fn rescale(sample: u16, from: u8, to: u8) -> u16 {
+    if from == to {
+        return sample;
+    }
+
+    let from_max = (1u32 << from) - 1;
+    let to_max = (1u32 << to) - 1;
+    ((u32::from(sample) * to_max + from_max / 2) / from_max) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpack_sub_byte_samples() {
+        assert_eq!(
+            unpack(&[0b1010_0000, 0b0100_0000], 3, 2, 1),
+            vec![1, 0, 1, 0, 1, 0]
+        );
+        assert_eq!(
+            unpack(&[0b1110_0100, 0b1100_0000], 5, 1, 2),
+            vec![3, 2, 1, 0, 3]
+        );
+        assert_eq!(unpack(&[0xab, 0xc0], 3, 1, 4), vec![0xa, 0xb, 0xc]);
+    }
+
+    fn ihdr(bit_depth: u8, color_type: ColorType) -> IHDR {
+        IHDR {
+            width: 3,
+            height: 1,
+            bit_depth,
+            color_type,
+            ..IHDR::default()
+        }
+    }
+
+    #[test]
+    fn color_key_at_bit_depth() {
+        let to_alpha = Transformations::new().trns_to_alpha(true);
+        let key = tRNS::RGB {
+            red: 1,
+            green: 2,
+            blue: 3,
+        };
+        let bitmap = apply(
+            to_alpha,
+            vec![1, 2, 3, 1, 2, 4, 1, 2, 3],
+            &ihdr(8, ColorType::RGB),
+            None,
+            Some(&key),
+            None,
+        )
+        .unwrap();
+        assert_eq!(bitmap.color_type, ColorType::RGBA);
+        assert_eq!(bitmap.buffer, vec![1, 2, 3, 0, 1, 2, 4, 255, 1, 2, 3, 0]);
+
+        // only the low byte of the second sample matches the key
+        let key = tRNS::Grayscale { grayscale: 0x1234 };
+        let bitmap = apply(
+            to_alpha,
+            vec![0x12, 0x34, 0x00, 0x34, 0x12, 0x35],
+            &ihdr(16, ColorType::Grayscale),
+            None,
+            Some(&key),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            bitmap.buffer,
+            vec![0x12, 0x34, 0, 0, 0x00, 0x34, 0xff, 0xff, 0x12, 0x35, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn predictable_output_formats() {
+        let rgba8 = Transformations::new()
+            .expand_palette(true)
+            .gray_to_rgb(true)
+            .add_alpha(true)
+            .strip_16(true);
+        let gray8 = Transformations::new().strip_alpha(true).strip_16(true);
+        let rgb16 = Transformations::new()
+            .expand_palette(true)
+            .gray_to_rgb(true)
+            .strip_alpha(true)
+            .expand_16(true);
+
+        // 2-bit samples are scaled up to 8 bits
+        let bitmap = apply(
+            rgba8,
+            vec![0b0011_1000],
+            &ihdr(2, ColorType::Grayscale),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            (bitmap.color_type, bitmap.bit_depth, bitmap.bpp),
+            (ColorType::RGBA, 8, 4)
+        );
+        assert_eq!(
+            bitmap.buffer,
+            vec![0, 0, 0, 255, 255, 255, 255, 255, 170, 170, 170, 255]
+        );
+
+        let plte = PLTE {
+            entries: vec![[1u8, 2, 3].into(), [4u8, 5, 6].into()],
+        };
+        let trns = tRNS::Indexed { entries: vec![7] };
+        let bitmap = apply(
+            rgb16,
+            vec![1, 0, 1],
+            &ihdr(8, ColorType::Indexed),
+            Some(&plte),
+            Some(&trns),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            (bitmap.color_type, bitmap.bit_depth, bitmap.bpp),
+            (ColorType::RGB, 16, 6)
+        );
+        assert_eq!(&bitmap.buffer[..6], &[4, 4, 5, 5, 6, 6]);
+
+        let bitmap = apply(
+            gray8,
+            vec![
+                0x12, 0x34, 0xff, 0xff, 0xff, 0x80, 0, 0, 0x00, 0x7f, 0xab, 0xcd,
+            ],
+            &ihdr(16, ColorType::GrayscaleAlpha),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            (bitmap.color_type, bitmap.bit_depth, bitmap.bpp),
+            (ColorType::Grayscale, 8, 1)
+        );
+        assert_eq!(bitmap.buffer, vec![0x12, 0xff, 0x00]);
+    }
+
+    #[test]
+    fn unusable_display_gamma_is_ignored() {
+        assert_eq!(
+            Transformations::new().gamma(2.2).display_gamma,
+            Some(220_000)
+        );
+        for &display_gamma in [0.0, -2.2, 1e-9, f64::NAN, f64::INFINITY].iter() {
+            let transformations = Transformations::new().gamma(display_gamma);
+            assert_eq!(transformations.display_gamma, None);
+        }
+    }
+}